@@ -0,0 +1,12 @@
+"""Tokenizes chat records with the chat template of a tokenizer and packs them into bins.
+
+    >>> import collate
+    >>> pipeline = collate.Pipeline("org/model")
+    >>> batch = pipeline.pack([[{"role": "user", "content": "Hi"}]], max_length=4096)
+
+`pack` returns a pyarrow RecordBatch, and `process_files` writes files like the collate CLI.
+"""
+
+from ._collate import Pipeline
+
+__all__ = ["Pipeline"]
@@ -0,0 +1,259 @@
+// Python bindings of the collate library, built with maturin
+//
+// The packed bins are handed to python as a pyarrow RecordBatch through the Arrow C Data
+// Interface, so the token columns are not copied into python lists.
+use arrow::pyarrow::ToPyArrow;
+use arrow::record_batch::RecordBatch;
+use collate::binpacking::{Dtype, Format};
+use collate::conversations::Mode;
+use collate::utils::parse_value;
+use collate::{
+    config, conversations, input, ChatTemplate, LabelPolicy, OutputOptions, Packable, Packer,
+    Pipeline, TokenDtypes, Tokenize, TokenizedInput, TokenizedPair,
+};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use std::fs;
+
+fn value_error(e: impl ToString) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+/// Tokenizes the records and packs them, or truncates them in their order without `pack`
+fn pack_records<T: Tokenize + Packable>(
+    pipeline: &Pipeline,
+    records: &[String],
+    max_length: i32,
+    pack: bool,
+    dtypes: &TokenDtypes,
+) -> collate::Result<RecordBatch> {
+    let samples: Vec<T> = pipeline.tokenize(records)?;
+    let bins = if pack {
+        Packer::pack(samples, max_length)
+    } else {
+        samples
+            .into_iter()
+            .map(|mut sample| {
+                sample.truncate(max_length);
+                sample
+            })
+            .collect()
+    };
+    Packer::to_record_batch(bins, dtypes)
+}
+
+/// Tokenizer and chat template, with the tokenizer.json path or huggingface `<org>/<name>`
+///
+/// The chat template, bos and eos tokens are read from the tokenizer_config.json of the
+/// tokenizer unless `chat_template` is given. `add_special_tokens` lets the tokenizer add its
+/// special tokens on top of the template, and `dedup_special_tokens` removes a doubled BOS or
+/// EOS. `separator_token` is appended to every sample, with its label masked unless
+/// `train_separator`. `label_shift` shifts the labels for trainers that do not shift them.
+/// `ignore_index` is the label of the tokens that are not trained on, and `train_roles` and
+/// `last_assistant_only` restrict the training to some messages of the sft records, rendering
+/// and tokenizing every prefix of the conversations again, and `loss_weights` adds a
+/// `loss_weights` column from their `weight` fields. `source_ids` adds the `id` field, or the
+/// line, of the records of every bin, and `document_ids` the index of the document of every
+/// token in its bin.
+#[pyclass(name = "Pipeline", module = "collate", frozen)]
+struct PyPipeline {
+    pipeline: Pipeline,
+}
+
+#[pymethods]
+impl PyPipeline {
+    #[new]
+    #[pyo3(signature = (
+        tokenizer,
+        chat_template=None,
+        bos_token=None,
+        eos_token=None,
+        add_special_tokens=false,
+        dedup_special_tokens=false,
+        separator_token=None,
+        train_separator=false,
+        label_shift=false,
+        ignore_index=-100,
+        train_roles=None,
+        last_assistant_only=false,
+        loss_weights=false,
+        source_ids=false,
+        document_ids=false,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        tokenizer: &str,
+        chat_template: Option<String>,
+        bos_token: Option<String>,
+        eos_token: Option<String>,
+        add_special_tokens: bool,
+        dedup_special_tokens: bool,
+        separator_token: Option<&str>,
+        train_separator: bool,
+        label_shift: bool,
+        ignore_index: i32,
+        train_roles: Option<Vec<String>>,
+        last_assistant_only: bool,
+        loss_weights: bool,
+        source_ids: bool,
+        document_ids: bool,
+    ) -> PyResult<Self> {
+        let template = match chat_template {
+            Some(chat_template) => ChatTemplate::new(chat_template, bos_token, eos_token),
+            None => ChatTemplate::from_config(config::read_config(tokenizer).map_err(value_error)?),
+        }
+        .map_err(value_error)?;
+        let tokenizer = collate::load_tokenizer(tokenizer).map_err(value_error)?;
+        let mut pipeline = Pipeline::new(tokenizer, template);
+        pipeline.add_special_tokens = add_special_tokens;
+        pipeline.dedup_special_tokens = dedup_special_tokens;
+        if let Some(token) = separator_token {
+            pipeline.separator_id = Some(pipeline.token_id(token).map_err(value_error)?);
+        }
+        pipeline.train_separator = train_separator;
+        pipeline.shift_labels = label_shift;
+        pipeline.labels = LabelPolicy {
+            ignore_index,
+            train_roles,
+            last_assistant_only,
+            loss_weights,
+        };
+        pipeline.source_ids = source_ids;
+        pipeline.document_ids = document_ids;
+        Ok(PyPipeline { pipeline })
+    }
+
+    #[getter]
+    fn vocab_size(&self) -> usize {
+        self.pipeline.vocab_size()
+    }
+
+    /// Tokenizes and packs the records in memory and returns a pyarrow RecordBatch
+    ///
+    /// A record is a list of messages, or a dict with a `conversations` field, or the
+    /// `prompt`, `chosen` and `rejected` fields in the preference mode. The source ids of the
+    /// records without an `id` field are their index in `records`.
+    #[pyo3(signature = (records, max_length=8192, mode="sft", dtype="int32", pack=true))]
+    fn pack(
+        &self,
+        py: Python<'_>,
+        records: Vec<Bound<'_, PyAny>>,
+        max_length: i32,
+        mode: &str,
+        dtype: &str,
+        pack: bool,
+    ) -> PyResult<PyObject> {
+        let json = py.import("json")?;
+        let lines = records
+            .iter()
+            .map(|record| {
+                let record = if record.is_instance_of::<PyList>() {
+                    let dict = PyDict::new(py);
+                    dict.set_item("conversations", record)?;
+                    dict.into_any()
+                } else {
+                    record.clone()
+                };
+                json.call_method1("dumps", (record,))?.extract::<String>()
+            })
+            .collect::<PyResult<Vec<String>>>()?;
+        let mode: Mode = parse_value(mode).map_err(value_error)?;
+        let mut dtypes = TokenDtypes::new(
+            parse_value::<Dtype>(dtype).map_err(value_error)?,
+            self.pipeline.vocab_size(),
+            max_length,
+            lines.len(),
+            self.pipeline.labels.ignore_index,
+        )
+        .map_err(value_error)?;
+        dtypes.loss_weights = self.pipeline.labels.loss_weights;
+        dtypes.source_ids = self.pipeline.source_ids;
+        dtypes.document_ids = self.pipeline.document_ids;
+        let pipeline = &self.pipeline;
+        let batch = py
+            .allow_threads(|| match mode {
+                Mode::Sft => {
+                    pack_records::<TokenizedInput>(pipeline, &lines, max_length, pack, &dtypes)
+                }
+                Mode::Preference => {
+                    pack_records::<TokenizedPair>(pipeline, &lines, max_length, pack, &dtypes)
+                }
+            })
+            .map_err(value_error)?;
+        batch.to_pyarrow(py)
+    }
+
+    /// Processes jsonl or parquet files into the output folder, like the collate CLI, and
+    /// returns the paths of the written files. Raises a ValueError for the options the CLI
+    /// rejects, and an OSError if a file fails
+    #[pyo3(signature = (
+        inputs,
+        output,
+        format="arrow",
+        mode="sft",
+        max_length=8192,
+        dtype="int32",
+        pack=true,
+        recursive=false,
+        batch_size=1000,
+        rows_per_shard=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn process_files(
+        &self,
+        py: Python<'_>,
+        inputs: Vec<String>,
+        output: &str,
+        format: &str,
+        mode: &str,
+        max_length: i32,
+        dtype: &str,
+        pack: bool,
+        recursive: bool,
+        batch_size: usize,
+        rows_per_shard: Option<usize>,
+    ) -> PyResult<Vec<String>> {
+        let format: Format = parse_value(format).map_err(value_error)?;
+        let mode: Mode = parse_value(mode).map_err(value_error)?;
+        let default = OutputOptions::default();
+        let dtypes = TokenDtypes {
+            loss_weights: self.pipeline.labels.loss_weights,
+            source_ids: self.pipeline.source_ids,
+            document_ids: self.pipeline.document_ids,
+            ..TokenDtypes::new(
+                parse_value::<Dtype>(dtype).map_err(value_error)?,
+                self.pipeline.vocab_size(),
+                max_length,
+                batch_size.max(default.row_group_size),
+                self.pipeline.labels.ignore_index,
+            )
+            .map_err(value_error)?
+        };
+        let options = OutputOptions {
+            format,
+            max_length,
+            batch_size,
+            rows_per_shard,
+            dtypes,
+            pack,
+            ignore_index: self.pipeline.labels.ignore_index,
+            ..default
+        };
+        options
+            .validate(mode, &self.pipeline.labels)
+            .map_err(value_error)?;
+        fs::create_dir_all(output).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        let files =
+            input::discover(&inputs, recursive).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        let pipeline = &self.pipeline;
+        py.allow_threads(|| conversations::process_files(files, output, pipeline, mode, &options))
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+}
+
+#[pymodule]
+fn _collate(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPipeline>()?;
+    Ok(())
+}
@@ -1,4 +1,10 @@
+use arrow::ipc::CompressionType;
 use clap::Parser;
+use collate::binpacking::{Dtype, Format};
+use collate::conversations::Mode;
+use collate::npy::NpyLayout;
+use collate::writers::IpcFormat;
+use parquet::basic::Compression;
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -7,13 +13,20 @@ use clap::Parser;
     long_about = "This program reads a folder with jsonl files and packs them into the chosen format"
 )]
 pub struct Cli {
-    #[clap(short, long, help="Input to the root folder, should contain jsonl files like so - path/*.jsonl or just a single file",
-    value_hint=clap::ValueHint::DirPath)]
-    pub input: String,
+    #[clap(short, long, help="Input to the root folder, should contain jsonl or parquet files like so - path/*.jsonl or just a single file.
+    Also accepts glob patterns like 'data/**/*.jsonl' and multiple inputs, or - to read jsonl from stdin",
+    value_hint=clap::ValueHint::DirPath, num_args=1.., required=true)]
+    pub input: Vec<String>,
     #[clap(short, long, help = "Output folder for the JSONL files, will write the jsonl as their own files
-    in the output folder. Eg. input/file.jsonl -> output/file.msgpack",
+    in the output folder. Eg. input/file.jsonl -> output/file.arrow, or - to write to stdout",
     value_hint=clap::ValueHint::DirPath)]
     pub output: String,
+    #[clap(
+        short,
+        long,
+        help = "Walk the input folders recursively, the sub folders are mirrored in the output folder"
+    )]
+    pub recursive: bool,
     #[clap(
         short,
         long,
@@ -30,8 +43,229 @@ pub struct Cli {
     #[clap(
         short,
         long,
-        help = "Format of output file, [jsonl,arrow,msgpack]",
-        default_value = "arrow"
+        help = "Format of output file. megatron writes the .bin and .idx of Megatron-LM's indexed dataset, npy writes a .npy file per column, mds writes a MosaicML streaming folder per input, webdataset writes tar shards with a .npy per column and sample",
+        value_enum,
+        ignore_case = true,
+        default_value_t = Format::Arrow
+    )]
+    pub format: Format,
+    #[clap(
+        long,
+        help = "Type of records in the input. Preference records have a prompt, chosen and rejected field",
+        value_enum,
+        ignore_case = true,
+        default_value_t = Mode::Sft
+    )]
+    pub mode: Mode,
+    #[clap(
+        long,
+        help = "Compression of the parquet output, [uncompressed,snappy,zstd(level)]",
+        default_value = "snappy"
+    )]
+    pub parquet_compression: Compression,
+    #[clap(
+        long,
+        help = "Maximum number of packed rows in a parquet row group",
+        default_value = "1000",
+        value_parser = parse_positive
+    )]
+    pub row_group_size: usize,
+    #[clap(
+        long,
+        help = "Number of packed rows in an arrow record batch, larger batches use more memory while writing",
+        default_value = "1000",
+        value_parser = parse_positive
+    )]
+    pub batch_size: usize,
+    #[clap(
+        long,
+        help = "Split the output into numbered shards with at most this many packed rows, eg. file-00000-of-00012.arrow",
+        value_parser = parse_positive
+    )]
+    pub rows_per_shard: Option<usize>,
+    #[clap(
+        long,
+        help = "Split the output into numbered shards of about this size, accepts units like 500MB or 1GiB. This is the size limit of the mds shards, 64MiB by default and below 4GiB",
+        value_parser = parse_bytes
     )]
-    pub format: String,
+    pub max_shard_bytes: Option<usize>,
+    #[clap(
+        long,
+        help = "Write dataset_info.json and state.json so the output folder loads with datasets.load_from_disk, arrow format only"
+    )]
+    pub hf_dataset: bool,
+    #[clap(
+        long,
+        help = "Arrow IPC format. The file format has a footer for random access and memory-mapping",
+        value_enum,
+        default_value_t = IpcFormat::Stream
+    )]
+    pub ipc_format: IpcFormat,
+    #[clap(
+        long,
+        help = "Compression of the arrow IPC buffers, [lz4,zstd]. Uncompressed by default",
+        value_parser = parse_ipc_compression
+    )]
+    pub ipc_compression: Option<CompressionType>,
+    #[clap(
+        long,
+        help = "Type of the token ids in arrow and parquet. Other than int32, the labels, position ids and list offsets also use the smallest type that fits. auto picks uint16 for vocabularies up to 65536 tokens",
+        value_enum,
+        ignore_case = true,
+        default_value_t = Dtype::Int32
+    )]
+    pub dtype: Dtype,
+    #[clap(
+        long,
+        help = "Write every input as its own row, truncated to the max length, instead of packing them"
+    )]
+    pub no_pack: bool,
+    #[clap(
+        long,
+        help = "With the megatron format, also write a uint8 <name>_loss_mask dataset that is 1 where the label is trained on"
+    )]
+    pub loss_mask: bool,
+    #[clap(
+        long,
+        help = "Layout of the npy format",
+        value_enum,
+        default_value_t = NpyLayout::Padded
+    )]
+    pub npy_layout: NpyLayout,
+    #[clap(
+        long,
+        help = "Token id used to pad the input ids in the padded npy layout, labels are padded with the ignore index",
+        default_value = "0"
+    )]
+    pub pad_id: i32,
+    #[clap(
+        long,
+        help = "Let the tokenizer add its special tokens, eg. a BOS from its post-processor, on top of the ones in the chat template"
+    )]
+    pub add_special_tokens: bool,
+    #[clap(
+        long,
+        help = "Remove a doubled BOS at the start or EOS at the end of the tokens, when both the chat template and the tokenizer add them"
+    )]
+    pub dedup_special_tokens: bool,
+    #[clap(
+        long,
+        help = "Token, or token id, appended to every sample before packing so the documents stay apart in a bin, eg. </s>. Samples that already end with it are left as is"
+    )]
+    pub separator_token: Option<String>,
+    #[clap(
+        long,
+        help = "Train on the separator token, by default its label is the ignore index"
+    )]
+    pub train_separator: bool,
+    #[clap(
+        long,
+        help = "Shift the labels so labels[i] is the label of input_ids[i + 1], for trainers that do not shift them. The last token of every document is masked, so no label crosses into the next document of a bin"
+    )]
+    pub label_shift: bool,
+    #[clap(
+        long,
+        help = "Label of the tokens that are not trained on, eg. the first token, masked roles and padding",
+        default_value = "-100",
+        allow_negative_numbers = true
+    )]
+    pub ignore_index: i32,
+    #[clap(
+        long,
+        help = "Only train on the messages of these roles, eg. assistant,tool. The tokens of the other messages, including the header of the next assistant message, are labelled with the ignore index. Every prefix of a conversation is rendered and tokenized again, which is quadratic in its number of messages. Needs the sft mode",
+        value_delimiter = ','
+    )]
+    pub train_roles: Option<Vec<String>>,
+    #[clap(
+        long,
+        help = "Only train on the last assistant message of every conversation, on top of --train-roles. Needs the sft mode"
+    )]
+    pub last_assistant_only: bool,
+    #[clap(
+        long,
+        help = "Write a float32 loss_weights column next to the labels, from the optional weight field of every record and message, 1 by default. A message weight is multiplied by the record weight. Needs the sft mode, not supported by the megatron format"
+    )]
+    pub loss_weights: bool,
+    #[clap(
+        long,
+        help = "Write a source_ids column with the source of every document of a bin, the id field of the record or its file and line, eg. data/file.jsonl:12. Needs the jsonl, arrow or parquet format"
+    )]
+    pub source_ids: bool,
+    #[clap(
+        long,
+        help = "Write a document_ids column with the index of the document of every token in its bin, eg. to build a block diagonal attention mask. Not supported by the megatron format"
+    )]
+    pub document_ids: bool,
+}
+
+/// Parses a size in bytes, with an optional decimal (KB, MB, GB) or binary (KiB, MiB, GiB) unit
+fn parse_bytes(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("Invalid size: {}", value))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "KIB" => 1 << 10,
+        "MIB" => 1 << 20,
+        "GIB" => 1 << 30,
+        _ => return Err(format!("Invalid unit: {}", unit)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Invalid size: {}", value))
+}
+
+/// Parses a count that must be at least 1, eg. a number of rows
+fn parse_positive(value: &str) -> Result<usize, String> {
+    match value.trim().parse() {
+        Ok(0) => Err("Must be at least 1".to_string()),
+        Ok(count) => Ok(count),
+        Err(_) => Err(format!("Invalid count: {}", value)),
+    }
+}
+
+/// Parses the arrow IPC compression codec
+fn parse_ipc_compression(value: &str) -> Result<CompressionType, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "lz4" => Ok(CompressionType::LZ4_FRAME),
+        "zstd" => Ok(CompressionType::ZSTD),
+        _ => Err(format!("Invalid IPC compression: {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("1024"), Ok(1024));
+        assert_eq!(parse_bytes("500MB"), Ok(500_000_000));
+        assert_eq!(parse_bytes("1GiB"), Ok(1 << 30));
+        assert!(parse_bytes("1TB").is_err());
+        assert!(parse_bytes("MB").is_err());
+        assert!(parse_bytes("99999999999GiB").is_err());
+    }
+
+    #[test]
+    fn test_parse_positive() {
+        assert_eq!(parse_positive("1000"), Ok(1000));
+        assert!(parse_positive("0").is_err());
+        assert!(parse_positive("-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_ipc_compression() {
+        assert_eq!(parse_ipc_compression("LZ4"), Ok(CompressionType::LZ4_FRAME));
+        assert_eq!(parse_ipc_compression("zstd"), Ok(CompressionType::ZSTD));
+        assert!(parse_ipc_compression("snappy").is_err());
+    }
 }
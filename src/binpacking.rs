@@ -1,20 +1,486 @@
 // Handles bin packing of TokenizedInput
 
-use crate::{conversations::TokenizedInput, time_it};
-use arrow::array::builder::{GenericListBuilder, PrimitiveBuilder};
-use arrow::array::types::Int32Type;
+use crate::conversations::{LabelPolicy, Mode};
+use crate::error::{Error, Result};
+use crate::mds::{self, MdsShard};
+use crate::megatron;
+use crate::npy::NpyLayout;
+use crate::writers::{to_record_batch, BatchWriter, Batched, IpcFormat, JsonlShard, ShardedOutput};
+use crate::{conversations::TokenizedInput, preference::TokenizedPair};
+use arrow::array::builder::{GenericListBuilder, PrimitiveBuilder, StringBuilder};
+use arrow::array::types::{Float32Type, Int32Type};
 use arrow::array::ArrowPrimitiveType;
-use arrow::array::LargeListArray;
+use arrow::array::{ArrayRef, LargeListArray};
 use arrow::datatypes::{DataType, Field, Schema};
-use arrow::ipc::writer::StreamWriter;
+use arrow::ipc::CompressionType;
 use arrow::record_batch::RecordBatch;
+use parquet::basic::Compression;
 use std::collections::BinaryHeap;
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::fs;
 use std::path::Path;
 use std::sync::Arc;
 
+use serde::Serialize;
+
 use indicatif::{ProgressBar, ProgressStyle};
+
+/// Anything that can be merged into a bin and written out as a row
+///
+/// The packing loop only relies on `length`, `merge` and `truncate`, while the arrow writer
+/// uses `schema` and `to_columns` to lay out the bins as columns.
+pub trait Packable: Ord + Clone + Default + Serialize + Send + 'static {
+    /// Number of tokens this entry takes up in a bin
+    fn length(&self) -> i32;
+    fn merge(&mut self, other: &Self);
+    fn truncate(&mut self, max_length: i32);
+    /// Size of the columns in memory, used to estimate the size of a shard
+    fn num_bytes(&self) -> usize;
+    fn schema(dtypes: &TokenDtypes) -> Schema;
+    /// Moves the bins into the columns of the schema, without copying the token vectors again
+    fn to_columns(bins: Vec<Self>, schema: &Schema) -> Vec<ArrayRef>;
+}
+
+/// Type of the token ids, `--dtype`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dtype {
+    #[default]
+    Int32,
+    Auto,
+    Uint16,
+    Uint32,
+    Int64,
+}
+
+/// Arrow types of the token columns
+///
+/// The bins are always built as `i32`, the columns are cast to these types when they are
+/// written out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenDtypes {
+    pub input_ids: DataType,
+    pub labels: DataType,
+    pub position_ids: DataType,
+    /// 64-bit list offsets, only needed if a record batch holds more than i32::MAX tokens
+    pub large_list: bool,
+    /// Write a float32 loss_weights column after the labels
+    pub loss_weights: bool,
+    /// Write a document_ids column after the position ids, the index of the document of
+    /// every token in its bin
+    pub document_ids: bool,
+    /// Write a source_ids column of strings, with the source of every document of a bin
+    pub source_ids: bool,
+}
+
+impl Default for TokenDtypes {
+    fn default() -> Self {
+        TokenDtypes {
+            input_ids: DataType::Int32,
+            labels: DataType::Int32,
+            position_ids: DataType::Int32,
+            large_list: true,
+            loss_weights: false,
+            document_ids: false,
+            source_ids: false,
+        }
+    }
+}
+
+impl TokenDtypes {
+    /// Picks the column types for `--dtype`
+    ///
+    /// `int32` keeps the original `LargeList<Int32>` columns. Any other choice sets the type of
+    /// the input ids, with `auto` picking `uint16` for vocabularies up to 65536 tokens, while the
+    /// labels, position ids and list offsets use the smallest type that fits, the labels also
+    /// holding `ignore_index`. A type too small for the vocabulary is refused.
+    pub fn new(
+        dtype: Dtype,
+        vocab_size: usize,
+        max_length: i32,
+        rows_per_batch: usize,
+        ignore_index: i32,
+    ) -> Result<Self, String> {
+        let input_ids = match dtype {
+            Dtype::Int32 => DataType::Int32,
+            Dtype::Auto if vocab_size <= u16::MAX as usize + 1 => DataType::UInt16,
+            Dtype::Auto => DataType::Int32,
+            Dtype::Uint16 => DataType::UInt16,
+            Dtype::Uint32 => DataType::UInt32,
+            Dtype::Int64 => DataType::Int64,
+        };
+        let max_id = match input_ids {
+            DataType::UInt16 => u16::MAX as usize,
+            DataType::Int32 => i32::MAX as usize,
+            DataType::UInt32 => u32::MAX as usize,
+            _ => usize::MAX,
+        };
+        if vocab_size > 0 && vocab_size - 1 > max_id {
+            return Err(format!(
+                "Dtype {} is too small for a vocabulary of {} tokens",
+                input_ids, vocab_size
+            ));
+        }
+        if dtype == Dtype::Int32 {
+            return Ok(TokenDtypes::default());
+        }
+        // labels also hold the ignore index, -100 by default, so they need a signed type
+        let labels = if vocab_size <= i16::MAX as usize + 1 && i16::try_from(ignore_index).is_ok() {
+            DataType::Int16
+        } else {
+            DataType::Int32
+        };
+        let position_ids = if max_length as usize <= u16::MAX as usize + 1 {
+            DataType::UInt16
+        } else {
+            DataType::Int32
+        };
+        let large_list = rows_per_batch.saturating_mul(max_length as usize) > i32::MAX as usize;
+        Ok(TokenDtypes {
+            input_ids,
+            labels,
+            position_ids,
+            large_list,
+            loss_weights: false,
+            document_ids: false,
+            source_ids: false,
+        })
+    }
+
+    fn list(&self, item: &DataType) -> DataType {
+        let field = Arc::new(Field::new_list_field(item.clone(), true));
+        if self.large_list {
+            DataType::LargeList(field)
+        } else {
+            DataType::List(field)
+        }
+    }
+}
+
+fn token_field(name: &str, data_type: DataType) -> Field {
+    Field::new(name, data_type, false)
+}
+
+/// The input ids, labels and position ids fields, and the document ids if they are written,
+/// with a prefix for the preference branches
+fn token_fields(prefix: &str, dtypes: &TokenDtypes) -> Vec<Field> {
+    let mut fields = vec![
+        token_field(
+            &format!("{}input_ids", prefix),
+            dtypes.list(&dtypes.input_ids),
+        ),
+        token_field(&format!("{}labels", prefix), dtypes.list(&dtypes.labels)),
+        token_field(
+            &format!("{}position_ids", prefix),
+            dtypes.list(&dtypes.position_ids),
+        ),
+    ];
+    // there are never more documents in a bin than positions
+    if dtypes.document_ids {
+        fields.push(token_field(
+            &format!("{}document_ids", prefix),
+            dtypes.list(&dtypes.position_ids),
+        ));
+    }
+    fields
+}
+
+/// The source ids of the documents of each bin, after the token fields
+fn source_field(dtypes: &TokenDtypes) -> Field {
+    token_field("source_ids", dtypes.list(&DataType::Utf8))
+}
+
+impl Packable for TokenizedInput {
+    fn length(&self) -> i32 {
+        self.length
+    }
+    fn merge(&mut self, other: &TokenizedInput) {
+        self.input_ids.extend(other.input_ids.clone());
+        self.labels.extend(other.labels.clone());
+        self.loss_weights.extend(other.loss_weights.clone());
+        self.position_ids.extend(other.position_ids.clone());
+        // the documents of the other bin come after the ones of this bin
+        let offset = self.document_ids.last().map_or(0, |id| id + 1);
+        self.document_ids
+            .extend(other.document_ids.iter().map(|id| id + offset));
+        self.length += other.length;
+        self.source_ids.extend(other.source_ids.clone());
+    }
+    fn truncate(&mut self, max_length: i32) {
+        self.input_ids.truncate(max_length as usize);
+        self.labels.truncate(max_length as usize);
+        self.loss_weights.truncate(max_length as usize);
+        self.position_ids.truncate(max_length as usize);
+        self.document_ids.truncate(max_length as usize);
+        self.length = self.input_ids.len() as i32;
+    }
+    fn num_bytes(&self) -> usize {
+        (self.input_ids.len()
+            + self.labels.len()
+            + self.position_ids.len()
+            + self.document_ids.len())
+            * size_of::<i32>()
+            + self.loss_weights.len() * size_of::<f32>()
+            + self.source_ids.iter().map(String::len).sum::<usize>()
+    }
+    fn schema(dtypes: &TokenDtypes) -> Schema {
+        let mut fields = token_fields("", dtypes);
+        if dtypes.loss_weights {
+            let loss_weights = token_field("loss_weights", dtypes.list(&DataType::Float32));
+            fields.insert(2, loss_weights);
+        }
+        if dtypes.source_ids {
+            fields.push(source_field(dtypes));
+        }
+        Schema::new(fields)
+    }
+    fn to_columns(mut bins: Vec<TokenizedInput>, schema: &Schema) -> Vec<ArrayRef> {
+        let sources = take_sources(&mut bins);
+        let mut columns = input_columns(bins, "", schema);
+        if schema.index_of("source_ids").is_ok() {
+            columns.push(source_column(sources));
+        }
+        columns
+    }
+}
+
+/// Moves the source ids out of the bins
+fn take_sources(bins: &mut [TokenizedInput]) -> Vec<Vec<String>> {
+    bins.iter_mut()
+        .map(|bin| std::mem::take(&mut bin.source_ids))
+        .collect()
+}
+
+/// The token columns of the inputs, with the loss weights after the labels and the document
+/// ids after the position ids if they are in the schema
+fn input_columns(bins: Vec<TokenizedInput>, prefix: &str, schema: &Schema) -> Vec<ArrayRef> {
+    let mut input_ids = Vec::with_capacity(bins.len());
+    let mut labels = Vec::with_capacity(bins.len());
+    let mut weights = Vec::with_capacity(bins.len());
+    let mut position_ids = Vec::with_capacity(bins.len());
+    let mut document_ids = Vec::with_capacity(bins.len());
+    for bin in bins {
+        input_ids.push(bin.input_ids);
+        labels.push(bin.labels);
+        weights.push(bin.loss_weights);
+        position_ids.push(bin.position_ids);
+        document_ids.push(bin.document_ids);
+    }
+    let inputs_listarray = from_iter_primitive_no_option::<Int32Type, _>(input_ids);
+    let labels_listarray = from_iter_primitive_no_option::<Int32Type, _>(labels);
+    let positions_listarray = from_iter_primitive_no_option::<Int32Type, _>(position_ids);
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(inputs_listarray),
+        Arc::new(labels_listarray),
+        Arc::new(positions_listarray),
+    ];
+    let in_schema = |name: &str| schema.index_of(&format!("{}{}", prefix, name)).is_ok();
+    if in_schema("document_ids") {
+        let documents_listarray = from_iter_primitive_no_option::<Int32Type, _>(document_ids);
+        columns.push(Arc::new(documents_listarray));
+    }
+    if in_schema("loss_weights") {
+        let weights_listarray = from_iter_primitive_no_option::<Float32Type, _>(weights);
+        columns.insert(2, Arc::new(weights_listarray));
+    }
+    columns
+}
+
+/// The source ids of the bins as a list column of strings
+fn source_column(sources: Vec<Vec<String>>) -> ArrayRef {
+    let mut builder =
+        GenericListBuilder::<i64, _>::with_capacity(StringBuilder::new(), sources.len());
+    for bin in sources {
+        for source in bin {
+            builder.values().append_value(source);
+        }
+        builder.append(true);
+    }
+    Arc::new(builder.finish())
+}
+
+// A pair is only as long as its longest branch, so as long as the pair lengths fit in a bin,
+// both the chosen and rejected bins fit as well and a pair is never split
+impl Packable for TokenizedPair {
+    fn length(&self) -> i32 {
+        self.length
+    }
+    fn merge(&mut self, other: &TokenizedPair) {
+        self.chosen.merge(&other.chosen);
+        self.rejected.merge(&other.rejected);
+        self.length += other.length;
+    }
+    fn truncate(&mut self, max_length: i32) {
+        self.chosen.truncate(max_length);
+        self.rejected.truncate(max_length);
+        self.length = self.chosen.length.max(self.rejected.length);
+    }
+    fn num_bytes(&self) -> usize {
+        self.chosen.num_bytes() + self.rejected.num_bytes()
+    }
+    // the loss weights are only written for the sft records, and the source ids once for both
+    // branches
+    fn schema(dtypes: &TokenDtypes) -> Schema {
+        let mut fields = token_fields("chosen_", dtypes);
+        fields.extend(token_fields("rejected_", dtypes));
+        if dtypes.source_ids {
+            fields.push(source_field(dtypes));
+        }
+        Schema::new(fields)
+    }
+    fn to_columns(bins: Vec<TokenizedPair>, schema: &Schema) -> Vec<ArrayRef> {
+        let (mut chosen, rejected): (Vec<TokenizedInput>, Vec<TokenizedInput>) = bins
+            .into_iter()
+            .map(|bin| (bin.chosen, bin.rejected))
+            .unzip();
+        let sources = take_sources(&mut chosen);
+        let mut columns = input_columns(chosen, "chosen_", schema);
+        columns.extend(input_columns(rejected, "rejected_", schema));
+        if schema.index_of("source_ids").is_ok() {
+            columns.push(source_column(sources));
+        }
+        columns
+    }
+}
+
+/// Format of the output files, `--format`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Jsonl,
+    #[default]
+    Arrow,
+    Parquet,
+    Megatron,
+    Npy,
+    Mds,
+    Webdataset,
+}
+
+impl Format {
+    /// Extension of the output files, an mds output is a folder without it
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Jsonl => "jsonl",
+            Format::Arrow => "arrow",
+            Format::Parquet => "parquet",
+            Format::Megatron => "bin",
+            Format::Npy => "npy",
+            Format::Mds => "mds",
+            Format::Webdataset => "tar",
+        }
+    }
+}
+
+/// The name of the format on the command line
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = clap::ValueEnum::to_possible_value(self).expect("Format has no value");
+        f.write_str(value.get_name())
+    }
+}
+
+/// Options for packing and writing the bins
+#[derive(Clone, Debug)]
+pub struct OutputOptions {
+    pub format: Format,
+    pub max_length: i32,
+    pub parquet_compression: Compression,
+    /// Maximum number of bins in a parquet row group
+    pub row_group_size: usize,
+    /// Number of bins in an arrow record batch
+    pub batch_size: usize,
+    pub rows_per_shard: Option<usize>,
+    pub max_shard_bytes: Option<usize>,
+    pub ipc_format: IpcFormat,
+    pub ipc_compression: Option<CompressionType>,
+    pub dtypes: TokenDtypes,
+    /// Pack the inputs into bins, otherwise every input is written as its own row
+    pub pack: bool,
+    /// Also write a uint8 loss mask dataset for the megatron format
+    pub loss_mask: bool,
+    pub npy_layout: NpyLayout,
+    /// Padding of the input ids in the padded npy layout
+    pub pad_id: i32,
+    /// Label of the tokens that are not trained on
+    pub ignore_index: i32,
+}
+
+/// The defaults of the CLI
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            format: Format::Arrow,
+            max_length: 8192,
+            parquet_compression: Compression::SNAPPY,
+            row_group_size: 1000,
+            batch_size: 1000,
+            rows_per_shard: None,
+            max_shard_bytes: None,
+            ipc_format: IpcFormat::Stream,
+            ipc_compression: None,
+            dtypes: TokenDtypes::default(),
+            pack: true,
+            loss_mask: false,
+            npy_layout: NpyLayout::Padded,
+            pad_id: 0,
+            ignore_index: crate::conversations::IGNORE_INDEX,
+        }
+    }
+}
+
+impl OutputOptions {
+    /// Checks the combinations of options that the formats and modes do not support, before
+    /// any file is processed
+    pub fn validate(&self, mode: Mode, labels: &LabelPolicy) -> Result<()> {
+        let unsupported = |message: String| Err(Error::Unsupported(message));
+        if self.batch_size == 0 || self.row_group_size == 0 || self.rows_per_shard == Some(0) {
+            return unsupported(
+                "The batch size, row group size and rows per shard must be at least 1".to_string(),
+            );
+        }
+        if self.format == Format::Megatron && mode != Mode::Sft {
+            return unsupported("The megatron format only supports the sft mode".to_string());
+        }
+        if (labels.train_roles.is_some() || labels.last_assistant_only || labels.loss_weights)
+            && mode != Mode::Sft
+        {
+            return unsupported(
+                "--train-roles, --last-assistant-only and --loss-weights need the sft mode"
+                    .to_string(),
+            );
+        }
+        let supports_sources = [Format::Jsonl, Format::Arrow, Format::Parquet];
+        if self.dtypes.source_ids && !supports_sources.contains(&self.format) {
+            return unsupported(format!(
+                "The {} format does not support --source-ids",
+                self.format
+            ));
+        }
+        // the megatron dataset only holds the input ids and the loss mask
+        if self.format == Format::Megatron && self.dtypes.loss_weights {
+            return unsupported("The megatron format does not support --loss-weights".to_string());
+        }
+        if self.format == Format::Megatron && self.dtypes.document_ids {
+            return unsupported("The megatron format does not support --document-ids".to_string());
+        }
+        if self.format == Format::Mds
+            && self
+                .max_shard_bytes
+                .is_some_and(|bytes| bytes > mds::MAX_SHARD_BYTES)
+        {
+            return unsupported(format!(
+                "The mds format needs --max-shard-bytes of at most {} bytes",
+                mds::MAX_SHARD_BYTES
+            ));
+        }
+        if self.format == Format::Megatron && !megatron::supports_dtype(&self.dtypes.input_ids) {
+            return unsupported(format!(
+                "The megatron format does not support the {} dtype",
+                self.dtypes.input_ids
+            ));
+        }
+        Ok(())
+    }
+}
+
 /// python reference implementation
 /// while i < limit:
 // if curr_length == 0:
@@ -30,73 +496,128 @@ use indicatif::{ProgressBar, ProgressStyle};
 // else:
 // curr_length = 0
 // return Dataset.from_list(bins)
-/// Takes in a max heap of TokenizedInput and bins them into a max_length
-// fn writer(
-//     rx: Receiver<TokenizedInput>,
-//     arrow_path: String,
-//     schema: Schema,
-// ) -> Result<(), Box<dyn std::error::Error>> {
-//     let path = Path::new(&arrow_path);
-//     let mut buffer = File::create(path).expect("create file error");
-//     let mut writer =
-//         StreamWriter::try_new_buffered(&mut buffer, &schema).expect("Error creating writer");
-//     while let Ok(input) = rx.recv() {
-//         write_bin_to_writer(input, &mut writer, &schema);
-//     }
-//     writer.finish().expect("Error finishing writing to file");
-//     Ok(())
-// }
-pub fn bin_and_save(mut inputs: BinaryHeap<TokenizedInput>, max_length: i32, arrow_path: String) {
-    println!("Dispatching binning and saving to {}", &arrow_path);
+/// Packs the inputs into bins of at most `max_length`, longest first, and passes every
+/// finished bin to `write`
+///
+/// Inputs longer than `max_length` are truncated and written as their own bin.
+pub fn pack<T: Packable>(mut inputs: BinaryHeap<T>, max_length: i32, mut write: impl FnMut(T)) {
     let mut curr_length = 0;
-    let mut curr_bin = TokenizedInput::new();
-    let schema = Schema::new(vec![
-        Field::new(
-            "input_ids",
-            DataType::LargeList(Arc::new(Field::new_list_field(DataType::Int32, true))),
-            false,
-        ),
-        Field::new(
-            "labels",
-            DataType::LargeList(Arc::new(Field::new_list_field(DataType::Int32, true))),
-            false,
-        ),
-        Field::new(
-            "position_ids",
-            DataType::LargeList(Arc::new(Field::new_list_field(DataType::Int32, true))),
-            false,
-        ),
-    ]);
-    let mut record_vec = Vec::new();
+    let mut curr_bin = T::default();
     while let Some(mut input) = inputs.pop() {
-        if input.length >= max_length {
-            // add directly to record_vec
+        if input.length() >= max_length {
+            // add directly as its own bin
             input.truncate(max_length);
-            record_vec.push(input);
+            write(input);
             continue;
         }
         // always starts here
         if curr_length == 0 {
-            curr_length = input.length;
+            curr_length = input.length();
             curr_bin.merge(&input);
-        } else if curr_length + input.length <= max_length {
-            curr_length += input.length;
+        } else if curr_length + input.length() <= max_length {
+            curr_length += input.length();
             curr_bin.merge(&input);
         } else {
-            curr_length = input.length;
-            record_vec.push(curr_bin.clone());
-            // tx.send(curr_bin).expect("Error sending to channel");
-            curr_bin = input;
+            curr_length = input.length();
+            write(std::mem::replace(&mut curr_bin, input));
         }
     }
-    let mut buffer = File::create(&arrow_path).expect("create file error");
-    let mut writer =
-        StreamWriter::try_new_buffered(&mut buffer, &schema).expect("Error creating writer");
-    let msg = format!("Writing to {}", arrow_path);
-    time_it!(msg, write_bin_to_writer(record_vec, &mut writer, &schema));
-    // explicitly drop the writer to free memory
-    drop(writer);
+    if curr_length > 0 {
+        write(curr_bin);
+    }
+}
+
+/// Packs samples in memory, for callers that write the bins themselves
+///
+/// ```
+/// use collate::{Packable, Packer, TokenizedInput};
+///
+/// let samples = vec![
+///     TokenizedInput::from_ids(vec![1, 2, 3]),
+///     TokenizedInput::from_ids(vec![4, 5]),
+///     TokenizedInput::from_ids(vec![6]),
+/// ];
+/// let bins = Packer::pack(samples, 4);
+/// let lengths: Vec<i32> = bins.iter().map(|bin| bin.length()).collect();
+/// assert_eq!(lengths, vec![3, 3]);
+/// ```
+pub struct Packer;
+
+impl Packer {
+    /// Packs the samples into bins of at most `max_length` tokens, longest first
+    pub fn pack<T: Packable>(samples: impl IntoIterator<Item = T>, max_length: i32) -> Vec<T> {
+        let mut bins = Vec::new();
+        pack(samples.into_iter().collect(), max_length, |bin| {
+            bins.push(bin)
+        });
+        bins
+    }
+
+    /// Lays out the bins as a record batch with the columns of the output files
+    pub fn to_record_batch<T: Packable>(bins: Vec<T>, dtypes: &TokenDtypes) -> Result<RecordBatch> {
+        to_record_batch(bins, &Arc::new(T::schema(dtypes)))
+    }
 }
+
+/// Packs the inputs with `pack`, or with `--no-pack` writes every input as its own row,
+/// truncated to `max_length` and longest first. Stops writing at the first error.
+fn bin_inputs<T: Packable>(
+    mut inputs: BinaryHeap<T>,
+    options: &OutputOptions,
+    mut write: impl FnMut(T) -> Result<()>,
+) -> Result<()> {
+    if options.pack {
+        let mut result = Ok(());
+        pack(inputs, options.max_length, |bin| {
+            if result.is_ok() {
+                result = write(bin);
+            }
+        });
+        return result;
+    }
+    while let Some(mut input) = inputs.pop() {
+        input.truncate(options.max_length);
+        write(input)?;
+    }
+    Ok(())
+}
+
+/// Packs the inputs and writes the bins in record batches to `path`, or to its shards, with
+/// the writers created by `create`
+pub fn bin_save<T, W>(
+    inputs: BinaryHeap<T>,
+    options: &OutputOptions,
+    path: String,
+    mut create: impl FnMut(&str, &Arc<Schema>, &OutputOptions) -> Result<W>,
+) -> Result<Vec<String>>
+where
+    T: Packable,
+    W: BatchWriter,
+{
+    eprintln!("Dispatching binning and saving to {}", &path);
+    let schema = Arc::new(T::schema(&options.dtypes));
+    let mut output = ShardedOutput::new(path, options, |path: &str| {
+        let writer = create(path, &schema, options)?;
+        Ok(Batched::new(writer, schema.clone(), options.batch_size))
+    });
+    bin_inputs(inputs, options, |bin| output.write(bin))?;
+    output.finish()
+}
+
+/// MDS shards are written to a folder per input, with an index.json of the shards
+pub fn bin_save_to_mds<T: Packable>(
+    inputs: BinaryHeap<T>,
+    options: &OutputOptions,
+    mds_folder: String,
+) -> Result<Vec<String>> {
+    fs::create_dir_all(&mds_folder)?;
+    let options = mds::shard_options(options);
+    let shard_path = Path::new(&mds_folder).join(mds::SHARD_FILENAME);
+    let shard_path = shard_path.to_str().expect("Invalid file path").to_string();
+    let shards = bin_save(inputs, &options, shard_path, MdsShard::create)?;
+    mds::write_index::<T>(shards, &options)
+}
+
 fn from_iter_primitive_no_option<T, I>(iter: I) -> LargeListArray
 where
     T: ArrowPrimitiveType,
@@ -116,87 +637,163 @@ where
     builder.finish()
 }
 
-// TODO: Can we implement a streaming writer?
-// fn writer(
-//     rx: Receiver<TokenizedInput>,
-//     arrow_path: String,
-//     schema: Schema,
-// ) -> Result<(), Box<dyn std::error::Error>> {
-//     let path = Path::new(&arrow_path);
-//     let mut buffer = File::create(path).expect("create file error");
-//     let mut writer =
-//         StreamWriter::try_new_buffered(&mut buffer, &schema).expect("Error creating writer");
-//     while let Ok(input) = rx.recv() {
-//         write_bin_to_writer(input, &mut writer, &schema);
-//     }
-//     writer.finish().expect("Error finishing writing to file");
-//     Ok(())
-// }
-fn write_bin_to_writer<W>(bin: Vec<TokenizedInput>, writer: &mut StreamWriter<W>, schema: &Schema)
-where
-    W: std::io::Write,
-{
-    let inputs_listarray =
-        from_iter_primitive_no_option::<Int32Type, _>(bin.iter().map(|bin| bin.input_ids.clone()));
-    let labels_listarray =
-        from_iter_primitive_no_option::<Int32Type, _>(bin.iter().map(|bin| bin.labels.clone()));
-    let positions_listarray = from_iter_primitive_no_option::<Int32Type, _>(
-        bin.iter().map(|bin| bin.position_ids.clone()),
-    );
-
-    let batch = RecordBatch::try_new(
-        Arc::new(schema.clone()),
-        vec![
-            Arc::new(inputs_listarray),
-            Arc::new(labels_listarray),
-            Arc::new(positions_listarray),
-        ],
-    )
-    .expect("Error creating record batch");
-
-    writer.write(&batch).expect("Error writing to file");
-    writer.finish().expect("Error finishing writing to file");
-    // explicitly drop the batch to free memory
-    drop(batch);
-}
-
-pub fn bin_save_to_jsonl(
-    mut inputs: BinaryHeap<TokenizedInput>,
-    max_length: i32,
+pub fn bin_save_to_jsonl<T: Packable>(
+    inputs: BinaryHeap<T>,
+    options: &OutputOptions,
     jsonl_path: String,
-) {
+) -> Result<Vec<String>> {
     let style = ProgressStyle::with_template("Writing: [{elapsed_precise} / {eta_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {per_sec}")
     .expect("Invalid progress style");
     let pb = ProgressBar::new(inputs.len() as u64);
     pb.set_style(style);
-    let mut curr_length = 0;
-    let mut curr_bin = TokenizedInput::new();
-    let file = File::create(Path::new(&jsonl_path)).expect("Create file error");
-    let mut writer = BufWriter::new(file);
+    let mut output = ShardedOutput::new(jsonl_path, options, JsonlShard::create);
 
-    // Note: For now, we allow that there is possibility that the first entry is already
-    // greater than the max_length
-    while let Some(input) = inputs.pop() {
+    bin_inputs(inputs, options, |bin| {
         pb.inc(1);
-        // always starts here
-        if curr_length == 0 {
-            curr_length = input.length;
-            curr_bin.merge(&input);
-        } else if curr_length + input.length <= max_length {
-            curr_length += input.length;
-            curr_bin.merge(&input);
-        } else {
-            curr_length = input.length;
-            write_bin_to_jsonl(curr_bin, &mut writer);
-            curr_bin = input;
+        output.write(bin)
+    })?;
+    pb.finish();
+    let paths = output.finish()?;
+    eprintln!("Finished writing to file");
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversations::Tokenize;
+    use arrow::array::AsArray;
+
+    #[test]
+    fn test_pack() {
+        let mut inputs = BinaryHeap::new();
+        for ids in [vec![1, 2, 3, 4, 5, 6], vec![1, 2], vec![1, 2, 3], vec![1]] {
+            inputs.push(TokenizedInput::from_ids(ids));
         }
+        let mut bins = Vec::new();
+        pack(inputs, 5, |bin| bins.push(bin));
+        let lengths: Vec<i32> = bins.iter().map(|bin| bin.length).collect();
+        // the longest input is truncated, the rest is packed longest first
+        assert_eq!(lengths, vec![5, 5, 1]);
+        assert_eq!(bins[1].position_ids, vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_token_dtypes() {
+        assert_eq!(
+            TokenDtypes::new(Dtype::Int32, 128256, 8192, 1000, -100),
+            Ok(TokenDtypes::default())
+        );
+        let dtypes = TokenDtypes::new(Dtype::Auto, 32000, 8192, 1000, -100).unwrap();
+        assert_eq!(dtypes.input_ids, DataType::UInt16);
+        assert_eq!(dtypes.labels, DataType::Int16);
+        assert_eq!(dtypes.position_ids, DataType::UInt16);
+        assert!(!dtypes.large_list);
+        let dtypes = TokenDtypes::new(Dtype::Auto, 128256, 131072, 1_000_000, -100).unwrap();
+        assert_eq!(dtypes.input_ids, DataType::Int32);
+        assert_eq!(dtypes.labels, DataType::Int32);
+        assert_eq!(dtypes.position_ids, DataType::Int32);
+        assert!(dtypes.large_list);
+        // an ignore index outside of int16 widens the labels
+        let dtypes = TokenDtypes::new(Dtype::Auto, 32000, 8192, 1000, -40000).unwrap();
+        assert_eq!(dtypes.labels, DataType::Int32);
+        // the largest id of a 65536 token vocabulary still fits
+        assert!(TokenDtypes::new(Dtype::Uint16, 65536, 8192, 1000, -100).is_ok());
+        assert!(TokenDtypes::new(Dtype::Uint16, 128256, 8192, 1000, -100).is_err());
+        assert_eq!(crate::utils::parse_value("UInt16"), Ok(Dtype::Uint16));
+        assert!(crate::utils::parse_value::<Dtype>("float32").is_err());
+    }
+
+    #[test]
+    fn test_source_and_document_ids() {
+        let input = |ids: Vec<i32>, source: &str| {
+            let mut input = TokenizedInput::from_ids(ids);
+            Tokenize::set_source(&mut input, source.to_string());
+            Tokenize::set_document_ids(&mut input);
+            input
+        };
+        let mut bin = input(vec![1, 2, 3], "a.jsonl:1");
+        bin.merge(&input(vec![4, 5], "b"));
+        bin.merge(&input(vec![6], "a.jsonl:3"));
+        assert_eq!(bin.document_ids, vec![0, 0, 0, 1, 1, 2]);
+        assert_eq!(bin.source_ids, vec!["a.jsonl:1", "b", "a.jsonl:3"]);
+
+        let dtypes = TokenDtypes {
+            document_ids: true,
+            source_ids: true,
+            ..TokenDtypes::new(Dtype::Auto, 32000, 8192, 1000, -100).unwrap()
+        };
+        let batch = Packer::to_record_batch(vec![bin], &dtypes).unwrap();
+        let names: Vec<&str> = batch
+            .schema_ref()
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "input_ids",
+                "labels",
+                "position_ids",
+                "document_ids",
+                "source_ids"
+            ]
+        );
+        let sources = batch.column(4).as_list::<i32>().value(0);
+        assert_eq!(sources.as_string::<i32>().value(2), "a.jsonl:3");
     }
-    write_bin_to_jsonl(curr_bin, &mut writer);
-    println!("Finished writing to file");
-    writer.flush().expect("Error finishing writing to file");
-}
 
-fn write_bin_to_jsonl(bin: TokenizedInput, writer: &mut BufWriter<File>) {
-    let json = serde_json::to_string(&bin).expect("Error serializing to json");
-    writeln!(writer, "{}", json).expect("Error writing to file");
+    #[test]
+    fn test_validate() {
+        let labels = LabelPolicy::default();
+        let megatron = OutputOptions {
+            format: Format::Megatron,
+            ..OutputOptions::default()
+        };
+        assert!(megatron.validate(Mode::Sft, &labels).is_ok());
+        assert!(megatron.validate(Mode::Preference, &labels).is_err());
+        let uint32 = OutputOptions {
+            dtypes: TokenDtypes::new(Dtype::Uint32, 100, 8, 1, -100).unwrap(),
+            ..megatron.clone()
+        };
+        assert!(uint32.validate(Mode::Sft, &labels).is_err());
+        let mut loss_weights = megatron.clone();
+        loss_weights.dtypes.loss_weights = true;
+        assert!(loss_weights.validate(Mode::Sft, &labels).is_err());
+        let mut document_ids = megatron.clone();
+        document_ids.dtypes.document_ids = true;
+        assert!(document_ids.validate(Mode::Sft, &labels).is_err());
+
+        let last_assistant_only = LabelPolicy {
+            last_assistant_only: true,
+            ..LabelPolicy::default()
+        };
+        let options = OutputOptions::default();
+        assert!(options.validate(Mode::Sft, &last_assistant_only).is_ok());
+        assert!(options
+            .validate(Mode::Preference, &last_assistant_only)
+            .is_err());
+
+        let mut npy = OutputOptions {
+            format: Format::Npy,
+            rows_per_shard: Some(0),
+            ..OutputOptions::default()
+        };
+        assert!(npy.validate(Mode::Sft, &labels).is_err());
+        npy.rows_per_shard = Some(1);
+        assert!(npy.validate(Mode::Sft, &labels).is_ok());
+        let mds = OutputOptions {
+            format: Format::Mds,
+            max_shard_bytes: Some(mds::MAX_SHARD_BYTES + 1),
+            ..OutputOptions::default()
+        };
+        assert!(mds.validate(Mode::Sft, &labels).is_err());
+
+        npy.dtypes.source_ids = true;
+        assert!(matches!(
+            npy.validate(Mode::Sft, &labels),
+            Err(Error::Unsupported(_))
+        ));
+    }
 }
@@ -1,26 +1,59 @@
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
-use std::sync::{Arc, Mutex};
-use std::{fs, path::Path};
+use std::path::Path;
+use std::rc::Rc;
 
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
-use rayon::prelude::*;
+use indicatif::{ProgressBar, ProgressStyle};
 
-use crate::{binpacking, globals, template, time_it};
+use crate::binpacking::{self, Format, Packable};
+use crate::error::{Error, Result};
+use crate::megatron::MegatronShard;
+use crate::npy::NpyShard;
+use crate::pipeline::Pipeline;
+use crate::webdataset::TarShard;
+use crate::writers::{ArrowShard, ParquetShard};
+use crate::{input, preference, template};
+
+/// Label of the tokens that are not trained on, the default of pytorch's cross entropy
+pub const IGNORE_INDEX: i32 = -100;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Conversation {
     #[serde(alias = "conversations")]
     conversation: Vec<template::TextMessage>,
+    /// Loss weight of the record, with `--loss-weights`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    weight: Option<f32>,
+    /// Source id of the record, with `--source-ids`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+}
+
+/// The `id` field of a record as a source id, strings without their quotes
+pub(crate) fn source_id(id: serde_json::Value) -> String {
+    match id {
+        serde_json::Value::String(id) => id,
+        id => id.to_string(),
+    }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Default, Serialize)]
 pub struct TokenizedInput {
     pub input_ids: Vec<i32>, // use i32 for arrow
     pub labels: Vec<i32>,
+    /// Loss weight of each token, empty unless `LabelPolicy::loss_weights`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub loss_weights: Vec<f32>,
     pub position_ids: Vec<i32>,
+    /// Index of the document of each token in its bin, empty unless `--document-ids`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub document_ids: Vec<i32>,
     pub length: i32,
+    /// Source ids of the documents of the bin, empty unless `--source-ids`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub source_ids: Vec<String>,
 }
 
 impl Ord for TokenizedInput {
@@ -44,127 +77,511 @@ impl PartialEq for TokenizedInput {
 impl Eq for TokenizedInput {}
 
 impl TokenizedInput {
-    pub fn new() -> Self {
+    /// Builds the input from token ids, the labels are a copy of the ids with the first
+    /// token masked
+    pub fn from_ids(input_ids: Vec<i32>) -> Self {
+        let labels = input_ids.clone();
+        TokenizedInput::with_labels(input_ids, labels, IGNORE_INDEX)
+    }
+
+    /// Builds the input from token ids and their labels, the first token is always masked
+    /// with `ignore_index` as there is nothing before it
+    pub fn with_labels(input_ids: Vec<i32>, mut labels: Vec<i32>, ignore_index: i32) -> Self {
+        if let Some(first) = labels.first_mut() {
+            *first = ignore_index;
+        }
+        let position_ids = (0..input_ids.len() as i32).collect();
+        let length = input_ids.len() as i32;
         TokenizedInput {
-            input_ids: Vec::new(),
-            labels: Vec::new(),
-            position_ids: Vec::new(),
-            length: 0,
-        }
-    }
-    pub fn merge(&mut self, other: &TokenizedInput) {
-        // Change the method to take &mut self instead of &self
-        self.input_ids.extend(other.input_ids.clone());
-        self.labels.extend(other.labels.clone());
-        self.position_ids.extend(other.position_ids.clone());
-        self.length += other.length;
-    }
-    pub fn truncate(&mut self, max_length: i32) {
-        self.input_ids.truncate(max_length as usize);
-        self.labels.truncate(max_length as usize);
-        self.position_ids.truncate(max_length as usize);
-        self.length = self.input_ids.len() as i32;
-    }
-}
-// This function is used in the read_jsonl function
-// It is used to parse the jsonl file and tokenize the conversation
-fn parse_and_tokenize(item: &str, ct: template::ChatTemplate) -> TokenizedInput {
-    let conv: Conversation = serde_json::from_str(item).unwrap();
-    let result = ct.apply(conv.conversation).unwrap();
-    let input_ids: Vec<u32> = globals::tokenize(&result).get_ids().to_owned();
-    let input_ids: Vec<i32> = input_ids.iter().map(|x| *x as i32).collect();
-    let mut labels: Vec<i32> = input_ids.clone();
-    // replace labels.0 with -100
-    labels[0] = -100;
-    let position_ids = (0..input_ids.len() as i32).collect();
-    let length = input_ids.len() as i32;
-    TokenizedInput {
-        input_ids,
-        labels,
-        position_ids,
-        length,
-    }
-}
-
-fn tokenize_jsonl(jsonl_path: &str, ct: template::ChatTemplate) -> BinaryHeap<TokenizedInput> {
-    // Is this faster than using BufReader?
-    println!("Reading jsonl file: {}", jsonl_path);
-    let jsonl = time_it!("Time to read: ", fs::read_to_string(jsonl_path).unwrap());
-    let length = jsonl.lines().count();
-    println!("Number of lines: {}", length);
+            input_ids,
+            labels,
+            loss_weights: Vec::new(),
+            position_ids,
+            document_ids: Vec::new(),
+            length,
+            source_ids: Vec::new(),
+        }
+    }
+
+    /// Shifts the labels one token to the left, so `labels[i]` is the label of
+    /// `input_ids[i + 1]`, and masks the last token which has nothing to predict
+    pub fn shift_labels(&mut self, ignore_index: i32) {
+        self.labels.rotate_left(1);
+        if !self.loss_weights.is_empty() {
+            self.loss_weights.rotate_left(1);
+        }
+        if let Some(last) = self.labels.last_mut() {
+            *last = ignore_index;
+        }
+    }
+
+    /// Appends a separator token, unless the input already ends with it
+    pub fn push_separator(&mut self, id: i32, label: i32) {
+        if self.input_ids.last() == Some(&id) {
+            return;
+        }
+        self.input_ids.push(id);
+        self.labels.push(label);
+        if let Some(weight) = self.loss_weights.last() {
+            self.loss_weights.push(*weight);
+        }
+        if let Some(document_id) = self.document_ids.last() {
+            self.document_ids.push(*document_id);
+        }
+        self.position_ids.push(self.length);
+        self.length += 1;
+    }
+}
+
+/// Number of lines that are rendered and tokenized together
+const TOKENIZE_BATCH_SIZE: usize = 1024;
+
+/// Which tokens of a conversation are trained on, the others are labelled `ignore_index`
+///
+/// By default every token but the first is trained on. `train_roles` only trains on the
+/// messages of these roles, and `last_assistant_only` only on the last assistant message, as
+/// well as the other roles of `train_roles` if they are set. `loss_weights` also weighs the
+/// tokens by the `weight` of their record and message, 1 by default.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelPolicy {
+    pub ignore_index: i32,
+    pub train_roles: Option<Vec<String>>,
+    pub last_assistant_only: bool,
+    pub loss_weights: bool,
+}
+
+impl Default for LabelPolicy {
+    fn default() -> Self {
+        LabelPolicy {
+            ignore_index: IGNORE_INDEX,
+            train_roles: None,
+            last_assistant_only: false,
+            loss_weights: false,
+        }
+    }
+}
+
+impl LabelPolicy {
+    /// Whether the labels depend on the roles of the messages
+    pub fn by_role(&self) -> bool {
+        self.train_roles.is_some() || self.last_assistant_only
+    }
+
+    /// Whether each message is trained on
+    pub fn trained(&self, messages: &[template::TextMessage]) -> Vec<bool> {
+        let last_assistant = messages.iter().rposition(|m| m.role == "assistant");
+        messages
+            .iter()
+            .enumerate()
+            .map(|(index, message)| {
+                let is_assistant = message.role == "assistant";
+                let role_trained = match &self.train_roles {
+                    Some(roles) => roles.contains(&message.role),
+                    None => !self.last_assistant_only || is_assistant,
+                };
+                role_trained
+                    && !(self.last_assistant_only && is_assistant && Some(index) != last_assistant)
+            })
+            .collect()
+    }
+}
+
+/// The texts of a record to tokenize, and how to label their tokens
+pub struct Rendered {
+    pub texts: Vec<String>,
+    pub labels: RecordLabels,
+    /// The `id` field of the record
+    pub id: Option<String>,
+}
+
+/// Which messages of a record are trained on, and the loss weights of the record
+pub struct RecordLabels {
+    /// Whether each message is trained on, empty unless the labels depend on the roles
+    pub trained: Vec<bool>,
+    /// Loss weight of each message, empty unless a message has its own weight
+    pub weights: Vec<f32>,
+    /// Loss weight of the whole record
+    pub weight: f32,
+}
+
+impl Default for RecordLabels {
+    fn default() -> Self {
+        RecordLabels {
+            trained: Vec::new(),
+            weights: Vec::new(),
+            weight: 1.0,
+        }
+    }
+}
+
+/// Number of leading ids shared by both
+pub(crate) fn common_prefix(left: &[i32], right: &[i32]) -> usize {
+    left.iter()
+        .zip(right.iter())
+        .take_while(|(left, right)| left == right)
+        .count()
+}
+
+/// A type of record in the input, rendered to one or more texts that are tokenized together
+pub trait Tokenize: Ord + Send + Sized {
+    /// Parses a jsonl line and renders the texts to tokenize
+    fn render(item: &str, ct: &template::ChatTemplate, policy: &LabelPolicy) -> Result<Rendered>;
+    /// Builds the record from the ids of the rendered texts, in the same order
+    fn from_ids(ids: Vec<Vec<i32>>, labels: &RecordLabels, policy: &LabelPolicy) -> Result<Self>;
+    /// Ends the record with a separator token, so the documents stay apart once packed
+    fn push_separator(&mut self, id: i32, label: i32);
+    /// Shifts the labels of the record for trainers that do not shift them
+    fn shift_labels(&mut self, ignore_index: i32);
+    /// Sets where the record comes from, kept in the source ids of its bin
+    fn set_source(&mut self, source: String);
+    /// Marks the tokens of the record as one document, numbered in its bin once packed
+    fn set_document_ids(&mut self);
+}
+
+// When the labels or weights differ between messages, every prefix of the conversation is
+// rendered as well, and the tokens of a message are the ones between the common prefixes of
+// the full text and the conversation up to the message and the one after it
+impl Tokenize for TokenizedInput {
+    fn render(item: &str, ct: &template::ChatTemplate, policy: &LabelPolicy) -> Result<Rendered> {
+        let conv: Conversation = serde_json::from_str(item)?;
+        let messages = conv.conversation;
+        let id = conv.id.map(source_id);
+        let mut labels = RecordLabels {
+            weight: conv.weight.unwrap_or(1.0),
+            ..RecordLabels::default()
+        };
+        if policy.loss_weights && messages.iter().any(|m| m.weight.is_some()) {
+            labels.weights = messages
+                .iter()
+                .map(|m| labels.weight * m.weight.unwrap_or(1.0))
+                .collect();
+        }
+        if !policy.by_role() && labels.weights.is_empty() {
+            return Ok(Rendered {
+                texts: vec![ct.apply(messages)?],
+                labels,
+                id,
+            });
+        }
+        let mut texts = vec![ct.apply(messages.clone())?];
+        for (index, message) in messages.iter().enumerate() {
+            let prefix = messages[..index].to_vec();
+            // the header of an assistant message is part of the prompt
+            let text = if message.role == "assistant" {
+                ct.apply_with_generation_prompt(prefix)
+            } else {
+                ct.apply(prefix)
+            };
+            texts.push(text.map_err(|source| Error::Prefix { index, source })?);
+        }
+        if policy.by_role() {
+            labels.trained = policy.trained(&messages);
+        }
+        Ok(Rendered { texts, labels, id })
+    }
+    fn from_ids(ids: Vec<Vec<i32>>, record: &RecordLabels, policy: &LabelPolicy) -> Result<Self> {
+        let mut ids = ids.into_iter();
+        let input_ids = ids.next().ok_or(Error::TextCount {
+            expected: 1,
+            found: 0,
+        })?;
+        let mut starts: Vec<usize> = ids
+            .map(|prefix| common_prefix(&prefix, &input_ids))
+            .collect();
+        let mut labels = if record.trained.is_empty() {
+            input_ids.clone()
+        } else {
+            vec![policy.ignore_index; input_ids.len()]
+        };
+        let mut loss_weights = if policy.loss_weights {
+            vec![record.weight; input_ids.len()]
+        } else {
+            Vec::new()
+        };
+        if !starts.is_empty() {
+            starts.push(input_ids.len());
+        }
+        let mut start = 0;
+        for (index, end) in starts.iter().skip(1).enumerate() {
+            start = start.max(starts[index]);
+            let end = (*end).max(start);
+            if record.trained.get(index) == Some(&true) {
+                labels[start..end].copy_from_slice(&input_ids[start..end]);
+            }
+            // the weights of the messages are only set with the loss weights
+            if let Some(weight) = record.weights.get(index) {
+                loss_weights[start..end].fill(*weight);
+            }
+            start = end;
+        }
+        let mut input = TokenizedInput::with_labels(input_ids, labels, policy.ignore_index);
+        input.loss_weights = loss_weights;
+        Ok(input)
+    }
+    fn push_separator(&mut self, id: i32, label: i32) {
+        TokenizedInput::push_separator(self, id, label);
+    }
+    fn shift_labels(&mut self, ignore_index: i32) {
+        TokenizedInput::shift_labels(self, ignore_index);
+    }
+    fn set_source(&mut self, source: String) {
+        self.source_ids = vec![source];
+    }
+    fn set_document_ids(&mut self) {
+        self.document_ids = vec![0; self.input_ids.len()];
+    }
+}
+
+fn tokenize_jsonl<T: Tokenize>(jsonl_path: &str, pipeline: &Pipeline) -> Result<BinaryHeap<T>> {
+    eprintln!("Reading file: {}", jsonl_path);
     let style = ProgressStyle::with_template("Tokenizing: [{elapsed_precise} / {eta_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {per_sec}")
         .expect("Invalid progress style");
-    let pb = ProgressBar::new(length as u64);
+    let pb = ProgressBar::new(0);
     pb.set_style(style);
-    let heap = Arc::new(Mutex::new(BinaryHeap::with_capacity(length)));
+    let mut records: Vec<T> = Vec::new();
 
-    // Main loop, parse, tokenize and push to heap
-    jsonl.par_lines().progress_with(pb).for_each(|item| {
-        let input: TokenizedInput = parse_and_tokenize(item, ct.clone());
-        let mut heap = heap.lock().unwrap();
-        heap.push(input);
-    });
-    // return the heap
-    let heap = heap.lock().unwrap();
-    heap.clone()
-}
-
-fn get_jsonl_path(jsonl_path: String, out_folder: String) -> String {
-    let path = Path::new(&jsonl_path);
-    let file_stem = path
-        .file_stem() // get the filename without extension
-        .expect("Invalid file path")
-        .to_str()
-        .expect("Invalid file path");
-    let mut out_path = Path::new(&out_folder).join(file_stem);
-    out_path.set_extension("jsonl");
-    out_path.to_str().unwrap().to_string()
-}
-
-fn get_arrow_path(jsonl_path: String, out_folder: String) -> String {
-    let path = Path::new(&jsonl_path);
-    let file_stem = path
-        .file_stem() // get the filename without extension
-        .expect("Invalid file path")
-        .to_str()
-        .expect("Invalid file path");
-    let mut out_path = Path::new(&out_folder).join(file_stem);
-    out_path.set_extension("arrow");
-    out_path.to_str().unwrap().to_string()
+    let mut line = 0;
+    for chunk in input::read_chunks(jsonl_path)? {
+        let chunk = chunk?;
+        let lines: Vec<&str> = chunk.lines().collect();
+        pb.inc_length(lines.len() as u64);
+        records.reserve(lines.len());
+        // Main loop, render the lines in parallel, then tokenize each batch at once, the
+        // tokenizer parallelises encode_batch on its own
+        for batch in lines.chunks(TOKENIZE_BATCH_SIZE) {
+            let source = |index: usize| format!("{}:{}", jsonl_path, line + index + 1);
+            records.extend(pipeline.tokenize_with_sources::<T, _>(batch, source)?);
+            line += batch.len();
+            pb.inc(batch.len() as u64);
+        }
+    }
+    pb.finish();
+    eprintln!("Number of lines: {}", line);
+    // heapify once instead of pushing every record
+    Ok(BinaryHeap::from(records))
+}
+
+/// Type of records in the input, `--mode`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    #[default]
+    Sft,
+    Preference,
 }
 
 pub fn single_jsonl_process(
-    jsonl_path: String,
-    max_length: i32,
+    input_file: input::InputFile,
     out_folder: String,
-    template: template::ChatTemplate,
-    format: String,
-    handles: &mut Vec<std::thread::JoinHandle<()>>,
-) -> std::io::Result<()> {
+    pipeline: &Pipeline,
+    mode: Mode,
+    options: binpacking::OutputOptions,
+    handles: &mut Vec<std::thread::JoinHandle<Result<Vec<String>>>>,
+) -> Result<()> {
     // read and tokenize in parallel
-    let inputs: BinaryHeap<TokenizedInput> = tokenize_jsonl(&jsonl_path, template);
-
-    // Dispatch the job to a thread because its not parallelisable and IO bound
-    let handle = std::thread::spawn(move || match format.to_ascii_lowercase().as_str() {
-        "arrow" => {
-            let arrow_path = get_arrow_path(jsonl_path, out_folder.to_string());
-            binpacking::bin_and_save(inputs, max_length, arrow_path);
+    match mode {
+        Mode::Sft => {
+            let inputs: BinaryHeap<TokenizedInput> = tokenize_jsonl(&input_file.path, pipeline)?;
+            dispatch_bins(inputs, input_file, out_folder, options, handles);
         }
-        "jsonl" => {
-            let jsonl_path = get_jsonl_path(jsonl_path, out_folder.to_string());
-            binpacking::bin_save_to_jsonl(inputs, max_length, jsonl_path);
+        Mode::Preference => {
+            let inputs: BinaryHeap<preference::TokenizedPair> =
+                tokenize_jsonl(&input_file.path, pipeline)?;
+            dispatch_bins(inputs, input_file, out_folder, options, handles);
         }
-        _ => {
-            let _ = Err::<(), anyhow::Error>(anyhow::anyhow!("Format not supported"));
+    }
+    Ok(())
+}
+
+/// Tokenizes the input files and writes their bins to the output folder, each file is written
+/// on its own thread while the next one is tokenized. Returns the paths of all the written
+/// files, or the first error.
+pub fn process_files(
+    files: Vec<input::InputFile>,
+    out_folder: &str,
+    pipeline: &Pipeline,
+    mode: Mode,
+    options: &binpacking::OutputOptions,
+) -> Result<Vec<String>> {
+    let mut handles = vec![];
+    let mut result = files.into_iter().try_for_each(|file| {
+        single_jsonl_process(
+            file,
+            out_folder.to_string(),
+            pipeline,
+            mode,
+            options.clone(),
+            &mut handles,
+        )
+    });
+
+    // wait for all threads to finish, also the files dispatched before an error
+    let mut paths = vec![];
+    for handle in handles {
+        let written = handle
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("A writer thread panicked").into()));
+        match written {
+            Ok(written) => paths.extend(written),
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(_) => {}
+        }
+    }
+    result.map(|_| paths)
+}
+
+fn dispatch_bins<T: Packable>(
+    inputs: BinaryHeap<T>,
+    input_file: input::InputFile,
+    out_folder: String,
+    options: binpacking::OutputOptions,
+    handles: &mut Vec<std::thread::JoinHandle<Result<Vec<String>>>>,
+) {
+    // Dispatch the job to a thread because its not parallelisable and IO bound
+    let handle = std::thread::spawn(move || {
+        let path = input_file.output_path(&out_folder, options.format.extension());
+        match options.format {
+            Format::Arrow => binpacking::bin_save(inputs, &options, path, ArrowShard::create),
+            Format::Jsonl => binpacking::bin_save_to_jsonl(inputs, &options, path),
+            Format::Parquet => binpacking::bin_save(inputs, &options, path, ParquetShard::create),
+            Format::Megatron => binpacking::bin_save(inputs, &options, path, MegatronShard::create),
+            Format::Npy => binpacking::bin_save(inputs, &options, path, NpyShard::create),
+            Format::Webdataset => {
+                // the keys are unique across the shards of a file
+                let next_key = Rc::new(Cell::new(0));
+                binpacking::bin_save(inputs, &options, path, |path, schema, _| {
+                    TarShard::create(path, schema, next_key.clone())
+                })
+            }
+            Format::Mds => {
+                // a folder named after the input, eg. out/file/index.json
+                let mds_folder = Path::new(&path).with_extension("");
+                let mds_folder = mds_folder.to_str().expect("Invalid file path").to_string();
+                binpacking::bin_save_to_mds(inputs, &options, mds_folder)
+            }
         }
     });
     handles.push(handle);
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pipeline::fixture_pipeline;
+    use std::fs;
+
+    #[test]
+    fn test_tokenize_jsonl() {
+        let root = std::env::temp_dir().join("collate_test_tokenize_jsonl");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("file.jsonl");
+        fs::write(
+            &path,
+            concat!(
+                r#"{"conversations": [{"role": "user", "content": "hi"}, {"role": "assistant", "content": "hello there !"}]}"#,
+                "\n",
+                r#"{"conversation": [{"role": "user", "content": "bye"}]}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        let inputs: BinaryHeap<TokenizedInput> =
+            tokenize_jsonl(path.to_str().unwrap(), &fixture_pipeline()).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        let inputs = inputs.into_sorted_vec();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0].input_ids, vec![8, 1, 6, 9]);
+        assert_eq!(inputs[1].input_ids, vec![8, 1, 3, 9, 2, 4, 5, 7, 9]);
+        assert_eq!(inputs[1].labels[0], -100);
+    }
+
+    #[test]
+    fn test_tokenize_jsonl_errors() {
+        let root = std::env::temp_dir().join("collate_test_tokenize_jsonl_errors");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("file.jsonl");
+        fs::write(&path, "{\"conversations\": \n").unwrap();
+        let invalid = tokenize_jsonl::<TokenizedInput>(path.to_str().unwrap(), &fixture_pipeline());
+        let missing = tokenize_jsonl::<TokenizedInput>(
+            root.join("missing.jsonl").to_str().unwrap(),
+            &fixture_pipeline(),
+        );
+        fs::remove_dir_all(&root).unwrap();
+        assert!(matches!(invalid, Err(Error::Record(_))));
+        assert!(matches!(missing, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_prefix_errors() {
+        let mut pipeline = fixture_pipeline();
+        pipeline.template = template::ChatTemplate::new(
+            "{% if messages|length < 2 %}{{ raise_exception('Expected a full conversation') }}{% endif %}{% for message in messages %}{{ message.content }}{% endfor %}".to_string(),
+            None,
+            None,
+        )
+        .unwrap();
+        let record = r#"{"conversations": [{"role": "user", "content": "hi"}, {"role": "assistant", "content": "hello"}]}"#;
+        // the full conversation renders, the prefixes are only needed for the labels
+        assert!(TokenizedInput::render(record, &pipeline.template, &pipeline.labels).is_ok());
+        pipeline.labels.train_roles = Some(vec!["assistant".to_string()]);
+        let result = TokenizedInput::render(record, &pipeline.template, &pipeline.labels);
+        assert!(matches!(result, Err(Error::Prefix { index: 0, .. })));
+    }
+
+    #[test]
+    fn test_process_files_errors() {
+        let root = std::env::temp_dir().join("collate_test_process_files_errors");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("file.jsonl");
+        fs::write(&path, "{\"conversations\": \n").unwrap();
+        let files = input::discover(&[path.to_str().unwrap().to_string()], false).unwrap();
+        let options = binpacking::OutputOptions::default();
+        let result = process_files(
+            files,
+            root.to_str().unwrap(),
+            &fixture_pipeline(),
+            Mode::Sft,
+            &options,
+        );
+        fs::remove_dir_all(&root).unwrap();
+        assert!(matches!(result, Err(Error::Record(_))));
+    }
+
+    #[test]
+    fn test_from_ids() {
+        let input = TokenizedInput::from_ids(vec![5, 6, 7]);
+        assert_eq!(input.labels, vec![-100, 6, 7]);
+        assert_eq!(input.position_ids, vec![0, 1, 2]);
+        assert_eq!(input.length, 3);
+    }
+
+    #[test]
+    fn test_push_separator() {
+        let mut input = TokenizedInput::from_ids(vec![5, 6]);
+        input.push_separator(2, -100);
+        assert_eq!(input.input_ids, vec![5, 6, 2]);
+        assert_eq!(input.labels, vec![-100, 6, -100]);
+        assert_eq!(input.position_ids, vec![0, 1, 2]);
+        assert_eq!(input.length, 3);
+        // already separated
+        input.push_separator(2, 2);
+        assert_eq!(input.length, 3);
+    }
+
+    #[test]
+    fn test_shift_labels() {
+        let mut left = TokenizedInput::from_ids(vec![5, 6, 7]);
+        left.shift_labels(-100);
+        assert_eq!(left.labels, vec![6, 7, -100]);
+        let mut right = TokenizedInput::from_ids(vec![8, 9]);
+        right.shift_labels(-100);
+        // no label crosses into the next document of the bin
+        left.merge(&right);
+        assert_eq!(left.input_ids, vec![5, 6, 7, 8, 9]);
+        assert_eq!(left.labels, vec![6, 7, -100, 9, -100]);
+    }
 
     #[test]
     fn test_merge() {
@@ -173,12 +590,14 @@ mod tests {
             labels: vec![1, 2, 3],
             position_ids: vec![0, 1, 2],
             length: 3,
+            ..Default::default()
         };
         let right = TokenizedInput {
             input_ids: vec![4, 5, 6],
             labels: vec![4, 5, 6],
             position_ids: vec![0, 1, 2],
             length: 3,
+            ..Default::default()
         };
         left.merge(&right);
         assert_eq!(left.input_ids, vec![1, 2, 3, 4, 5, 6]);
@@ -193,6 +612,7 @@ mod tests {
             labels: vec![1, 2, 3],
             position_ids: vec![0, 1, 2],
             length: 3,
+            ..Default::default()
         };
         input.truncate(2);
         assert_eq!(input.input_ids, vec![1, 2]);
@@ -209,18 +629,21 @@ mod tests {
             labels: vec![1, 2, 3],
             position_ids: vec![0, 1, 2],
             length: 1,
+            ..Default::default()
         });
         heap.push(TokenizedInput {
             input_ids: vec![4, 5, 6],
             labels: vec![4, 5, 6],
             position_ids: vec![0, 1, 2],
             length: 5,
+            ..Default::default()
         });
         heap.push(TokenizedInput {
             input_ids: vec![7, 8, 9],
             labels: vec![7, 8, 9],
             position_ids: vec![0, 1, 2],
             length: 2,
+            ..Default::default()
         });
         let mut sorted: Vec<TokenizedInput> = Vec::new();
         while let Some(item) = heap.pop() {
@@ -0,0 +1,31 @@
+// Errors of the library, the CLI reports them and exits
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid record: {0}")]
+    Record(#[from] serde_json::Error),
+    #[error("Error rendering the chat template: {0}")]
+    Template(#[from] minijinja::Error),
+    #[error("Error rendering the conversation up to message {index}, the labels need every prefix of it: {source}")]
+    Prefix {
+        index: usize,
+        source: minijinja::Error,
+    },
+    #[error("Error in the tokenizer: {0}")]
+    Tokenizer(#[from] tokenizers::Error),
+    #[error("Token {0} is not in the vocabulary")]
+    UnknownToken(String),
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("Expected {expected} rendered texts per record, found {found}")]
+    TextCount { expected: usize, found: usize },
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,178 @@
+// Handles the metadata files of a huggingface `datasets` folder
+//
+// `datasets.load_from_disk` expects a `state.json` listing the arrow files and a
+// `dataset_info.json` with the features, as written by `Dataset.save_to_disk`.
+use arrow::datatypes::{DataType, Schema};
+use arrow::ipc::reader::StreamReader;
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+
+pub const STATE_FILENAME: &str = "state.json";
+pub const INFO_FILENAME: &str = "dataset_info.json";
+
+/// Converts an arrow type to the `datasets` feature, eg. `{"dtype": "int32", "_type": "Value"}`
+fn feature(data_type: &DataType) -> io::Result<Value> {
+    let feature = match data_type {
+        DataType::List(field) => {
+            json!({"feature": feature(field.data_type())?, "_type": "Sequence"})
+        }
+        DataType::LargeList(field) => {
+            json!({"feature": feature(field.data_type())?, "_type": "LargeList"})
+        }
+        _ => {
+            let dtype = match data_type {
+                DataType::Boolean => "bool",
+                DataType::Int8 => "int8",
+                DataType::Int16 => "int16",
+                DataType::Int32 => "int32",
+                DataType::Int64 => "int64",
+                DataType::UInt8 => "uint8",
+                DataType::UInt16 => "uint16",
+                DataType::UInt32 => "uint32",
+                DataType::UInt64 => "uint64",
+                DataType::Float16 => "float16",
+                DataType::Float32 => "float32",
+                DataType::Float64 => "float64",
+                DataType::Utf8 => "string",
+                DataType::LargeUtf8 => "large_string",
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("No datasets feature for arrow type {}", data_type),
+                    ))
+                }
+            };
+            json!({"dtype": dtype, "_type": "Value"})
+        }
+    };
+    Ok(feature)
+}
+
+fn features(schema: &Schema) -> io::Result<Value> {
+    let mut features = serde_json::Map::new();
+    for field in schema.fields() {
+        features.insert(field.name().clone(), feature(field.data_type())?);
+    }
+    Ok(Value::Object(features))
+}
+
+/// Hash of the file names, sizes and schema, so a different output gets a different fingerprint
+fn fingerprint(out_folder: &str, filenames: &[String], schema: &Schema) -> io::Result<String> {
+    let mut hasher = DefaultHasher::new();
+    for filename in filenames {
+        filename.hash(&mut hasher);
+        fs::metadata(Path::new(out_folder).join(filename))?
+            .len()
+            .hash(&mut hasher);
+    }
+    schema.to_string().hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Writes `state.json` and `dataset_info.json` to the output folder
+///
+/// All the arrow files are listed as a single dataset, in the given order. The features are
+/// taken from the schema of the first file.
+pub fn write_metadata(out_folder: &str, paths: &[String]) -> io::Result<()> {
+    let first = paths
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No arrow files were written"))?;
+    let schema = StreamReader::try_new(File::open(first)?, None)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .schema();
+    let filenames: Vec<String> = paths
+        .iter()
+        .map(|path| {
+            let relative = Path::new(path)
+                .strip_prefix(out_folder)
+                .unwrap_or(Path::new(path));
+            relative.to_str().expect("Invalid file path").to_string()
+        })
+        .collect();
+
+    let state = json!({
+        "_data_files": filenames
+            .iter()
+            .map(|filename| json!({"filename": filename}))
+            .collect::<Vec<_>>(),
+        "_fingerprint": fingerprint(out_folder, &filenames, &schema)?,
+        "_format_columns": null,
+        "_format_kwargs": {},
+        "_format_type": null,
+        "_output_all_columns": false,
+        "_split": null,
+    });
+    let info = json!({
+        "citation": "",
+        "description": "",
+        "features": features(&schema)?,
+        "homepage": "",
+        "license": "",
+    });
+    fs::write(
+        Path::new(out_folder).join(STATE_FILENAME),
+        serde_json::to_string_pretty(&state)?,
+    )?;
+    fs::write(
+        Path::new(out_folder).join(INFO_FILENAME),
+        serde_json::to_string_pretty(&info)?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binpacking::{Format, OutputOptions, Packable, TokenDtypes};
+    use crate::conversations::TokenizedInput;
+    use crate::writers::fixture::{self, TestFolder};
+    use crate::writers::ArrowShard;
+
+    #[test]
+    fn test_features() {
+        let features = features(&TokenizedInput::schema(&TokenDtypes::default())).unwrap();
+        assert_eq!(
+            features["input_ids"],
+            json!({"feature": {"dtype": "int32", "_type": "Value"}, "_type": "LargeList"})
+        );
+        assert_eq!(
+            features.as_object().unwrap().keys().collect::<Vec<_>>(),
+            vec!["input_ids", "labels", "position_ids"]
+        );
+    }
+
+    #[test]
+    fn test_write_metadata() {
+        let folder = TestFolder::new("write_metadata");
+        let options = OutputOptions {
+            rows_per_shard: Some(1),
+            ..fixture::options(Format::Arrow)
+        };
+        let paths = fixture::write_bins(
+            folder.path("file.arrow"),
+            &options,
+            vec![vec![1, 2], vec![3]],
+            ArrowShard::create,
+        );
+
+        write_metadata(folder.root(), &paths).unwrap();
+        let state: Value = serde_json::from_slice(&folder.read(STATE_FILENAME)).unwrap();
+        let info: Value = serde_json::from_slice(&folder.read(INFO_FILENAME)).unwrap();
+        assert_eq!(
+            state["_data_files"],
+            json!([
+                {"filename": "file-00000-of-00002.arrow"},
+                {"filename": "file-00001-of-00002.arrow"}
+            ])
+        );
+        assert_eq!(state["_fingerprint"].as_str().unwrap().len(), 16);
+        assert_eq!(
+            info["features"]["labels"]["feature"],
+            json!({"dtype": "int32", "_type": "Value"})
+        );
+    }
+}
@@ -0,0 +1,457 @@
+// Handles reading of the input files
+//
+// Every input is turned into chunks of json lines, so that all formats share the same
+// parsing and tokenization path as jsonl.
+use arrow::json::LineDelimitedWriter;
+use flate2::read::MultiGzDecoder;
+use parquet::arrow::arrow_reader::{ArrowReaderMetadata, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ProjectionMask;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+use xz2::read::XzDecoder;
+
+use crate::error::Result;
+
+/// Path used for reading from stdin or writing to stdout
+pub const STDIO: &str = "-";
+
+/// Extensions of the files picked up when scanning a folder
+pub const SUPPORTED_EXTENSIONS: [&str; 2] = ["jsonl", "parquet"];
+
+/// Columns of a parquet file that are read as fields of the records, the others are skipped
+pub const RECORD_COLUMNS: [&str; 7] = [
+    "conversation",
+    "conversations",
+    "prompt",
+    "chosen",
+    "rejected",
+    "weight",
+    "id",
+];
+
+/// Extensions of compressed jsonl files, eg. file.jsonl.gz
+pub const COMPRESSED_EXTENSIONS: [&str; 4] = ["gz", "zst", "zstd", "xz"];
+
+/// Compression of an input file, detected from the leading magic bytes
+#[derive(Debug, PartialEq)]
+enum Codec {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Codec {
+    fn from_magic(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Codec::Gzip
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Codec::Zstd
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Codec::Xz
+        } else {
+            Codec::None
+        }
+    }
+}
+
+fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|ext| ext.to_str())
+}
+
+/// Removes the compression extension, if any, eg. file.jsonl.gz -> file.jsonl
+fn strip_compression(path: &Path) -> &Path {
+    match extension(path) {
+        Some(ext) if COMPRESSED_EXTENSIONS.contains(&ext) => {
+            path.file_stem().map(Path::new).unwrap_or(path)
+        }
+        _ => path,
+    }
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    match extension(path) {
+        Some(ext) if COMPRESSED_EXTENSIONS.contains(&ext) => {
+            extension(strip_compression(path)) == Some("jsonl")
+        }
+        Some(ext) => SUPPORTED_EXTENSIONS.contains(&ext),
+        None => false,
+    }
+}
+
+/// Name of the file without the compression and format extensions
+///
+/// data/file.jsonl.gz -> file, data/file.v2.parquet -> file.v2
+pub fn file_stem(path: &str) -> String {
+    strip_compression(Path::new(path))
+        .file_stem() // get the filename without extension
+        .expect("Invalid file path")
+        .to_str()
+        .expect("Invalid file path")
+        .to_string()
+}
+
+/// A file to process, along with its path relative to the input it was found in
+#[derive(Clone, Debug)]
+pub struct InputFile {
+    pub path: String,
+    /// Used to mirror the sub folders of the input in the output folder
+    pub relative: PathBuf,
+}
+
+impl InputFile {
+    /// Path of the output file, eg. data/lang=en/part-0.jsonl.gz -> out/lang=en/part-0.arrow
+    ///
+    /// Everything goes to stdout when the output folder is `-`.
+    pub fn output_path(&self, out_folder: &str, extension: &str) -> String {
+        if out_folder == STDIO {
+            return STDIO.to_string();
+        }
+        let folder = match self.relative.parent() {
+            Some(parent) => Path::new(out_folder).join(parent),
+            None => PathBuf::from(out_folder),
+        };
+        let file_stem = file_stem(self.relative.to_str().expect("Invalid file path"));
+        let out_path = folder.join(format!("{}.{}", file_stem, extension));
+        out_path.to_str().unwrap().to_string()
+    }
+}
+
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+/// Leading folders of a glob pattern, eg. data/**/*.jsonl -> data
+fn glob_root(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+fn input_file(path: &Path, root: &Path) -> InputFile {
+    InputFile {
+        path: path.to_str().expect("Invalid file path").to_string(),
+        relative: path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+    }
+}
+
+/// Finds the files to process for each input
+///
+/// An input can be a single file, a folder or a glob pattern like `data/**/*.jsonl`. Folders
+/// are only walked recursively when `recursive` is set, while glob patterns always follow
+/// the pattern. The results are sorted so the order does not depend on the file system.
+///
+/// `-` reads jsonl from stdin, the output is then named `stdin`.
+pub fn discover(inputs: &[String], recursive: bool) -> io::Result<Vec<InputFile>> {
+    let mut files = Vec::new();
+    for input in inputs {
+        if input == STDIO {
+            files.push(InputFile {
+                path: STDIO.to_string(),
+                relative: PathBuf::from("stdin.jsonl"),
+            });
+        } else if is_glob(input) {
+            let root = glob_root(input);
+            let paths =
+                glob::glob(input).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let mut found: Vec<InputFile> = paths
+                .filter_map(|path| path.ok())
+                .filter(|path| path.is_file() && is_supported(path))
+                .map(|path| input_file(&path, &root))
+                .collect();
+            found.sort_by(|a, b| a.path.cmp(&b.path));
+            files.extend(found);
+        } else if fs::metadata(input)?.is_file() {
+            let path = Path::new(input);
+            files.push(input_file(path, path.parent().unwrap_or(Path::new(""))));
+        } else {
+            let root = Path::new(input);
+            let max_depth = if recursive { usize::MAX } else { 1 };
+            for entry in WalkDir::new(root).max_depth(max_depth).sort_by_file_name() {
+                let entry = entry?;
+                if entry.file_type().is_file() && is_supported(entry.path()) {
+                    files.push(input_file(entry.path(), root));
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Opens the file, or stdin for `-`, and decompresses it on the fly if it is gzip, zstd or
+/// xz compressed
+pub fn open(path: &str) -> io::Result<Box<dyn Read>> {
+    let reader: Box<dyn Read> = if path == STDIO {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut reader = BufReader::new(reader);
+    let codec = Codec::from_magic(reader.fill_buf()?);
+    Ok(match codec {
+        Codec::None => Box::new(reader),
+        Codec::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Codec::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+    })
+}
+
+/// Reads the file as chunks of json lines
+///
+/// A jsonl file is decompressed and read into memory as a single chunk, while a parquet file is read one row
+/// group at a time, with each row written out as a json line.
+pub fn read_chunks(path: &str) -> Result<Box<dyn Iterator<Item = Result<String>>>> {
+    if extension(Path::new(path)) == Some("parquet") {
+        return Ok(Box::new(read_parquet(path)?));
+    }
+    let mut jsonl = String::new();
+    time_it!("Time to read: ", open(path)?.read_to_string(&mut jsonl)?);
+    Ok(Box::new(std::iter::once(Ok(jsonl))))
+}
+
+/// Reads the row groups of the file one at a time, the file is opened and its metadata parsed
+/// once for all of them
+fn read_parquet(path: &str) -> Result<impl Iterator<Item = Result<String>>> {
+    let file = File::open(path)?;
+    let metadata = ArrowReaderMetadata::load(&file, Default::default())?;
+    let num_row_groups = metadata.metadata().num_row_groups();
+    eprintln!(
+        "Number of rows: {} in {} row groups",
+        metadata.metadata().file_metadata().num_rows(),
+        num_row_groups
+    );
+    let projection = record_projection(&metadata);
+    Ok((0..num_row_groups).map(move |row_group| {
+        let file = file.try_clone()?;
+        read_row_group(file, metadata.clone(), projection.clone(), row_group)
+    }))
+}
+
+/// Only the record columns, or all of them if there is none so the records fail to parse with
+/// the missing field
+fn record_projection(metadata: &ArrowReaderMetadata) -> ProjectionMask {
+    let indices: Vec<usize> = metadata
+        .schema()
+        .fields()
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| RECORD_COLUMNS.contains(&field.name().as_str()))
+        .map(|(index, _)| index)
+        .collect();
+    if indices.is_empty() {
+        ProjectionMask::all()
+    } else {
+        ProjectionMask::roots(metadata.parquet_schema(), indices)
+    }
+}
+
+fn read_row_group(
+    file: File,
+    metadata: ArrowReaderMetadata,
+    projection: ProjectionMask,
+    row_group: usize,
+) -> Result<String> {
+    let reader = ParquetRecordBatchReaderBuilder::new_with_metadata(file, metadata)
+        .with_projection(projection)
+        .with_row_groups(vec![row_group])
+        .build()?;
+    let mut writer = LineDelimitedWriter::new(Vec::new());
+    for batch in reader {
+        writer.write(&batch?)?;
+    }
+    writer.finish()?;
+    String::from_utf8(writer.into_inner())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{ArrayRef, ListBuilder, StringBuilder, StructBuilder};
+    use arrow::datatypes::{DataType, Field, Fields, Schema};
+    use arrow::record_batch::RecordBatch;
+    use parquet::arrow::ArrowWriter;
+    use parquet::file::properties::WriterProperties;
+    use std::sync::Arc;
+
+    use std::io::Write;
+
+    use crate::template::TextMessage;
+
+    #[test]
+    fn test_is_supported() {
+        assert!(is_supported(Path::new("data/file.jsonl")));
+        assert!(is_supported(Path::new("data/file.parquet")));
+        assert!(is_supported(Path::new("data/file.jsonl.gz")));
+        assert!(is_supported(Path::new("data/file.jsonl.zst")));
+        assert!(!is_supported(Path::new("data/file.json")));
+        assert!(!is_supported(Path::new("data/file.json.gz")));
+        assert!(!is_supported(Path::new("data/file.gz")));
+        assert!(!is_supported(Path::new("data/file")));
+    }
+
+    #[test]
+    fn test_file_stem() {
+        assert_eq!(file_stem("data/file.jsonl"), "file");
+        assert_eq!(file_stem("data/file.jsonl.gz"), "file");
+        assert_eq!(file_stem("data/file.v2.jsonl.zst"), "file.v2");
+        assert_eq!(file_stem("data/file.parquet"), "file");
+    }
+
+    #[test]
+    fn test_output_path() {
+        let file = InputFile {
+            path: "data/lang=en/part-0.jsonl.gz".to_string(),
+            relative: PathBuf::from("lang=en/part-0.jsonl.gz"),
+        };
+        assert_eq!(file.output_path("out", "arrow"), "out/lang=en/part-0.arrow");
+        let file = InputFile {
+            path: "data/file.parquet".to_string(),
+            relative: PathBuf::from("file.parquet"),
+        };
+        assert_eq!(file.output_path("out", "jsonl"), "out/file.jsonl");
+        assert_eq!(file.output_path(STDIO, "jsonl"), STDIO);
+    }
+
+    #[test]
+    fn test_glob_root() {
+        assert_eq!(glob_root("data/**/*.jsonl"), PathBuf::from("data"));
+        assert_eq!(glob_root("data/lang=*/part-*.jsonl"), PathBuf::from("data"));
+        assert_eq!(glob_root("*.jsonl"), PathBuf::from(""));
+    }
+
+    #[test]
+    fn test_discover() {
+        let root = std::env::temp_dir().join("collate_test_discover");
+        let _ = fs::remove_dir_all(&root);
+        for name in [
+            "a.jsonl",
+            "notes.txt",
+            "lang=en/a.jsonl",
+            "lang=fr/b.parquet",
+        ] {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let root_str = root.to_str().unwrap().to_string();
+        let relative = |files: Vec<InputFile>| -> Vec<PathBuf> {
+            files.into_iter().map(|file| file.relative).collect()
+        };
+
+        let files = discover(std::slice::from_ref(&root_str), false).unwrap();
+        assert_eq!(relative(files), vec![PathBuf::from("a.jsonl")]);
+
+        let files = discover(std::slice::from_ref(&root_str), true).unwrap();
+        assert_eq!(
+            relative(files),
+            vec![
+                PathBuf::from("a.jsonl"),
+                PathBuf::from("lang=en/a.jsonl"),
+                PathBuf::from("lang=fr/b.parquet"),
+            ]
+        );
+
+        let pattern = format!("{}/**/a.jsonl", root_str);
+        let file = format!("{}/lang=fr/b.parquet", root_str);
+        let files = discover(&[pattern, file], false).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            relative(files),
+            vec![
+                PathBuf::from("a.jsonl"),
+                PathBuf::from("lang=en/a.jsonl"),
+                PathBuf::from("b.parquet"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_compressed() {
+        let jsonl = "{\"a\": 1}\n{\"a\": 2}\n";
+        let gzip = {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(jsonl.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        let zstd = zstd::encode_all(jsonl.as_bytes(), 0).unwrap();
+        let xz = {
+            let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+            encoder.write_all(jsonl.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        // the extension is not needed to detect the compression
+        for (name, bytes) in [("gz", gzip), ("zst", zstd), ("xz", xz)] {
+            let path = std::env::temp_dir().join(format!("collate_test_read_compressed_{}", name));
+            fs::write(&path, bytes).unwrap();
+            let chunks: Vec<String> = read_chunks(path.to_str().unwrap())
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(chunks, vec![jsonl.to_string()]);
+        }
+    }
+
+    #[test]
+    fn test_read_parquet() {
+        let fields = Fields::from(vec![
+            Field::new("role", DataType::Utf8, false),
+            Field::new("content", DataType::Utf8, false),
+        ]);
+        let mut builder = ListBuilder::new(StructBuilder::from_fields(fields.clone(), 4));
+        for content in ["Hi", "Hello", "Bye"] {
+            let messages = builder.values();
+            for role in ["user", "assistant"] {
+                messages
+                    .field_builder::<StringBuilder>(0)
+                    .unwrap()
+                    .append_value(role);
+                messages
+                    .field_builder::<StringBuilder>(1)
+                    .unwrap()
+                    .append_value(content);
+                messages.append(true);
+            }
+            builder.append(true);
+        }
+        let column: ArrayRef = Arc::new(builder.finish());
+        // not a field of the records, so it is not read
+        let source: ArrayRef = Arc::new(arrow::array::StringArray::from(vec!["a", "b", "c"]));
+        let schema = Schema::new(vec![
+            Field::new("conversation", column.data_type().clone(), false),
+            Field::new("source", DataType::Utf8, false),
+        ]);
+        let batch = RecordBatch::try_new(Arc::new(schema), vec![column, source]).unwrap();
+
+        let path = std::env::temp_dir().join("collate_test_read_parquet.parquet");
+        let props = WriterProperties::builder()
+            .set_max_row_group_size(2)
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), Some(props))
+                .unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let chunks: Vec<String> = read_chunks(path.to_str().unwrap())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        // one chunk per row group
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].lines().count(), 2);
+        let line: serde_json::Value =
+            serde_json::from_str(chunks[1].lines().next().unwrap()).unwrap();
+        let messages: Vec<TextMessage> =
+            serde_json::from_value(line["conversation"].clone()).unwrap();
+        assert_eq!(messages[1].role, "assistant");
+        assert_eq!(messages[1].content, "Bye");
+        assert!(line.get("source").is_none());
+    }
+}
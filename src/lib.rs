@@ -0,0 +1,47 @@
+//! Tokenizes chat and preference records with a chat template and packs them into bins of a
+//! maximum length, then writes the bins as arrow, parquet, jsonl and the training formats.
+//!
+//! The collate binary is a thin wrapper over this library, other services can reuse the same
+//! rendering, tokenization and packing.
+//!
+//! ```no_run
+//! use collate::{load_tokenizer, ChatTemplate, Packer, Pipeline, TokenizedInput};
+//!
+//! let template = ChatTemplate::new(
+//!     "{% for message in messages %}{{ message.role }}: {{ message.content }}\n{% endfor %}"
+//!         .to_string(),
+//!     None,
+//!     None,
+//! )?;
+//! let pipeline = Pipeline::new(load_tokenizer("tokenizer.json")?, template);
+//! let records = [
+//!     r#"{"conversations": [{"role": "user", "content": "Hi"}]}"#,
+//!     r#"{"conversations": [{"role": "user", "content": "Hello"}]}"#,
+//! ];
+//! let samples: Vec<TokenizedInput> = pipeline.tokenize(&records)?;
+//! let bins = Packer::pack(samples, 4096);
+//! # Ok::<(), collate::Error>(())
+//! ```
+pub mod binpacking;
+pub mod config;
+#[macro_use]
+pub mod utils;
+pub mod conversations;
+pub mod error;
+pub mod hf_dataset;
+pub mod input;
+pub mod mds;
+pub mod megatron;
+pub mod npy;
+pub mod pipeline;
+pub mod preference;
+pub mod template;
+pub mod webdataset;
+pub mod writers;
+
+pub use binpacking::{OutputOptions, Packable, Packer, TokenDtypes};
+pub use conversations::{LabelPolicy, RecordLabels, Rendered, Tokenize, TokenizedInput};
+pub use error::{Error, Result};
+pub use pipeline::{load_tokenizer, Pipeline};
+pub use preference::TokenizedPair;
+pub use template::{ChatTemplate, TextMessage};
@@ -1,70 +1,133 @@
+use std::collections::HashSet;
 use std::fs;
 
 use clap::Parser;
 use std::path::Path;
 
-pub mod args;
-pub mod binpacking;
-pub mod config;
-#[macro_use]
-pub mod utils;
-pub mod conversations;
-pub mod globals;
-pub mod template;
+use collate::binpacking::Format;
+use collate::writers::IpcFormat;
+use collate::{binpacking, config, conversations, hf_dataset, input, pipeline, ChatTemplate};
+
+mod args;
 
 fn main() -> std::io::Result<()> {
     let args = args::Cli::parse();
-    let folder: String = args.input;
     let out_folder: String = args.output;
+    let to_stdout = out_folder == input::STDIO;
     // check if output folder exists
-    if !Path::new(&out_folder).exists() {
+    if !to_stdout && !Path::new(&out_folder).exists() {
         fs::create_dir(&out_folder)?;
     }
+    let files = input::discover(&args.input, args.recursive)?;
+    if to_stdout && (args.rows_per_shard.is_some() || args.max_shard_bytes.is_some()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Sharding is not supported when writing to stdout",
+        ));
+    }
+    if args.hf_dataset
+        && (to_stdout || args.format != Format::Arrow || args.ipc_format != IpcFormat::Stream)
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--hf-dataset needs the arrow stream format and an output folder",
+        ));
+    }
+    // these formats write several files next to each other
+    let is_format = |formats: &[Format]| formats.contains(&args.format);
+    if to_stdout && is_format(&[Format::Megatron, Format::Npy, Format::Mds]) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("The {} format needs an output folder", args.format),
+        ));
+    }
+    if to_stdout && files.len() != 1 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Writing to stdout needs exactly one input file, found {}",
+                files.len()
+            ),
+        ));
+    }
+    // different inputs may end up with the same output name, eg. a/file.jsonl and b/file.jsonl
+    let mut outputs = HashSet::new();
+    for file in files.iter().filter(|_| !to_stdout) {
+        let output = file.output_path(&out_folder, "");
+        if !outputs.insert(output.clone()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "Multiple inputs are written to {}*, from {}",
+                    output, file.path
+                ),
+            ));
+        }
+        if let Some(parent) = Path::new(&output).parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
     let tokenizer: String = args.tokenizer;
 
-    globals::init_tokenizer(&tokenizer);
     // read config
-    let mut handles = vec![];
-    let config: config::TokenizerConfig = config::read_config(&tokenizer).unwrap();
-    let template = template::ChatTemplate::from_config(config);
-    let paths: Vec<String> = {
-        let md = fs::metadata(&folder)?;
-        if md.is_file() {
-            vec![folder]
-        } else {
-            fs::read_dir(&folder)?
-                .filter_map(|entry| {
-                    let entry = entry.ok()?;
-                    let path = entry.path();
-                    if path.extension()?.to_str()? == "jsonl" {
-                        Some(path.into_os_string().into_string().unwrap())
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>()
-        }
+    let config: config::TokenizerConfig =
+        config::read_config(&tokenizer).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let template = ChatTemplate::from_config(config).map_err(std::io::Error::other)?;
+    let tokenizer = pipeline::load_tokenizer(&tokenizer).map_err(std::io::Error::other)?;
+    let mut pipeline = pipeline::Pipeline::new(tokenizer, template);
+    pipeline.add_special_tokens = args.add_special_tokens;
+    pipeline.dedup_special_tokens = args.dedup_special_tokens;
+    if let Some(token) = &args.separator_token {
+        let id = pipeline
+            .token_id(token)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+        pipeline.separator_id = Some(id);
+    }
+    pipeline.train_separator = args.train_separator;
+    pipeline.shift_labels = args.label_shift;
+    pipeline.labels = conversations::LabelPolicy {
+        ignore_index: args.ignore_index,
+        train_roles: args.train_roles,
+        last_assistant_only: args.last_assistant_only,
+        loss_weights: args.loss_weights,
     };
-    globals::TOTAL_JSONL.fetch_add(
-        paths.len().try_into().unwrap(),
-        std::sync::atomic::Ordering::SeqCst,
-    );
-    paths
-        .into_iter() // filter only jsonl files
-        .for_each(|path| {
-            let _ = conversations::single_jsonl_process(
-                path,
-                args.max_length,
-                out_folder.clone(),
-                template.clone(),
-                args.format.clone(),
-                &mut handles,
-            );
-        });
-
-    // wait for all threads to finish
-    for handle in handles {
-        handle.join().unwrap();
+    pipeline.source_ids = args.source_ids;
+    pipeline.document_ids = args.document_ids;
+    let mut dtypes = binpacking::TokenDtypes::new(
+        args.dtype,
+        pipeline.vocab_size(),
+        args.max_length,
+        args.batch_size.max(args.row_group_size),
+        args.ignore_index,
+    )
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    dtypes.loss_weights = args.loss_weights;
+    dtypes.source_ids = args.source_ids;
+    dtypes.document_ids = args.document_ids;
+    let options = binpacking::OutputOptions {
+        format: args.format,
+        max_length: args.max_length,
+        parquet_compression: args.parquet_compression,
+        row_group_size: args.row_group_size,
+        batch_size: args.batch_size,
+        rows_per_shard: args.rows_per_shard,
+        max_shard_bytes: args.max_shard_bytes,
+        ipc_format: args.ipc_format,
+        ipc_compression: args.ipc_compression,
+        dtypes,
+        pack: !args.no_pack,
+        loss_mask: args.loss_mask,
+        npy_layout: args.npy_layout,
+        pad_id: args.pad_id,
+        ignore_index: args.ignore_index,
+    };
+    options
+        .validate(args.mode, &pipeline.labels)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let paths = conversations::process_files(files, &out_folder, &pipeline, args.mode, &options)
+        .map_err(std::io::Error::other)?;
+    if args.hf_dataset {
+        hf_dataset::write_metadata(&out_folder, &paths)?;
     }
 
     Ok(())
@@ -79,9 +142,12 @@ mod tests {
     #[test]
     fn test_tokenizer() {
         let tokenizer: String = "aisingapore/llama3.1-8b-cpt-sea-lionv3-instruct".to_string();
-        globals::init_tokenizer(&tokenizer);
+        let pipeline = pipeline::Pipeline::new(
+            pipeline::load_tokenizer(&tokenizer).unwrap(),
+            ChatTemplate::new(String::new(), None, None).unwrap(),
+        );
         let content = "Hello world";
-        let encoding = globals::tokenize(content);
+        let encoding = pipeline.encode(content).unwrap();
         println!("{:?}", encoding.get_ids());
     }
     #[test]
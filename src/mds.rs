@@ -0,0 +1,325 @@
+// Handles the MosaicML streaming (MDS) output, a folder of shards with an index.json
+//
+// A shard is the u32 number of samples, the u32 offsets of the samples from the start of the
+// shard, one more than the samples, then the samples. A sample is the u32 sizes of its
+// columns followed by their data, with the columns sorted by name.
+//
+// The token columns use the `ndarray:<dtype>` encoding of `NDArray` in
+// streaming/base/format/mds/encodings.py: the dtype is in the index, so a value is the u8
+// number of dimensions, the u8 dtype code and the values of the shape, then the data.
+use arrow::datatypes::{DataType, Schema};
+use arrow::record_batch::RecordBatch;
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::binpacking::{OutputOptions, Packable};
+use crate::error::{Error, Result};
+use crate::writers::{list_item_type, list_parts, value_bytes, BatchWriter};
+
+pub const INDEX_FILENAME: &str = "index.json";
+/// Name of the shards in the folder, they are numbered like the other sharded outputs
+pub const SHARD_FILENAME: &str = "shard.mds";
+/// Default size limit of `MDSWriter`
+const DEFAULT_SIZE_LIMIT: usize = 1 << 26;
+/// The offsets of the samples are u32, so a shard is at most 4GiB
+pub const MAX_SHARD_BYTES: usize = u32::MAX as usize;
+const MDS_VERSION: u64 = 2;
+
+/// Name of an arrow type in numpy, the dtype of the ndarray columns
+fn numpy_dtype(data_type: &DataType) -> Result<&'static str> {
+    Ok(match data_type {
+        DataType::UInt16 => "uint16",
+        DataType::Int16 => "int16",
+        DataType::UInt32 => "uint32",
+        DataType::Int32 => "int32",
+        DataType::Int64 => "int64",
+        DataType::Float32 => "float32",
+        _ => {
+            return Err(Error::Unsupported(format!(
+                "No numpy dtype for {}",
+                data_type
+            )))
+        }
+    })
+}
+
+/// Names and `ndarray:<dtype>` encodings of the columns, sorted by name
+fn column_encodings(schema: &Schema) -> Result<Vec<(String, String)>> {
+    let mut columns = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let dtype = numpy_dtype(list_item_type(field.data_type())?)?;
+            Ok((field.name().clone(), format!("ndarray:{}", dtype)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    columns.sort();
+    Ok(columns)
+}
+
+/// Header of a 1-D ndarray value, with the smallest unsigned dtype that fits the length
+fn ndarray_header(length: usize) -> Vec<u8> {
+    // dtype codes of uint8, uint16, uint32 and uint64
+    let mut header = vec![1];
+    if length <= u8::MAX as usize {
+        header.extend([4, length as u8]);
+    } else if length <= u16::MAX as usize {
+        header.push(5);
+        header.extend((length as u16).to_le_bytes());
+    } else if length <= u32::MAX as usize {
+        header.push(6);
+        header.extend((length as u32).to_le_bytes());
+    } else {
+        header.push(7);
+        header.extend((length as u64).to_le_bytes());
+    }
+    header
+}
+
+/// Size limits of the shards, `max_shard_bytes` is 64MiB by default like `MDSWriter`
+pub fn shard_options(options: &OutputOptions) -> OutputOptions {
+    OutputOptions {
+        max_shard_bytes: Some(options.max_shard_bytes.unwrap_or(DEFAULT_SIZE_LIMIT)),
+        ..options.clone()
+    }
+}
+
+/// One MDS shard in the folder of an input file, eg. out/file/shard-00000-of-00002.mds
+///
+/// Every bin is one sample. The samples are kept in memory until the shard is finished, as
+/// their offsets come first.
+pub struct MdsShard {
+    path: String,
+    /// Indices of the columns sorted by name
+    order: Vec<usize>,
+    samples: Vec<Vec<u8>>,
+}
+
+impl MdsShard {
+    pub fn create(path: &str, schema: &Arc<Schema>, _options: &OutputOptions) -> Result<Self> {
+        // the same encodings are written to the index
+        column_encodings(schema)?;
+        let mut order: Vec<usize> = (0..schema.fields().len()).collect();
+        order.sort_by_key(|index| schema.field(*index).name());
+        Ok(MdsShard {
+            path: path.to_string(),
+            order,
+            samples: Vec::new(),
+        })
+    }
+}
+
+impl BatchWriter for MdsShard {
+    fn write_batch(&mut self, batch: RecordBatch) -> Result<()> {
+        let columns: Vec<_> = self
+            .order
+            .iter()
+            .map(|index| {
+                let (values, lengths) = list_parts(batch.column(*index))?;
+                let item_size = values.data_type().primitive_width().unwrap();
+                Ok((value_bytes(&values), lengths, item_size))
+            })
+            .collect::<Result<_>>()?;
+        let mut starts = vec![0; columns.len()];
+        for row in 0..batch.num_rows() {
+            let mut head = Vec::with_capacity(4 * columns.len());
+            let mut body = Vec::new();
+            for ((bytes, lengths, item_size), start) in columns.iter().zip(starts.iter_mut()) {
+                let size = lengths[row] * item_size;
+                let header = ndarray_header(lengths[row]);
+                head.extend(((header.len() + size) as u32).to_le_bytes());
+                body.extend(header);
+                body.extend_from_slice(&bytes[*start..*start + size]);
+                *start += size;
+            }
+            head.extend(body);
+            self.samples.push(head);
+        }
+        Ok(())
+    }
+    fn finish(self) -> Result<Vec<String>> {
+        let mut offset = 4 + 4 * (self.samples.len() + 1);
+        let size = offset + self.samples.iter().map(Vec::len).sum::<usize>();
+        // a shard holds at least one sample, which may be larger than the limit
+        if size > MAX_SHARD_BYTES {
+            return Err(Error::Unsupported(format!(
+                "The mds shard {} would be {} bytes, larger than the {} bytes of its u32 offsets",
+                self.path, size, MAX_SHARD_BYTES
+            )));
+        }
+        let mut shard = Vec::with_capacity(size);
+        shard.extend((self.samples.len() as u32).to_le_bytes());
+        shard.extend((offset as u32).to_le_bytes());
+        for sample in &self.samples {
+            offset += sample.len();
+            shard.extend((offset as u32).to_le_bytes());
+        }
+        for sample in self.samples {
+            shard.extend(sample);
+        }
+        fs::write(&self.path, &shard)?;
+        Ok(vec![self.path])
+    }
+    /// The offset, the column sizes and the ndarray values of the sample
+    fn bin_bytes(&self, columns: &[(usize, usize)]) -> usize {
+        4 + columns
+            .iter()
+            .map(|(length, bytes)| 4 + ndarray_header(*length).len() + bytes)
+            .sum::<usize>()
+    }
+    /// The number of samples and the offset of the first one
+    fn footer_bytes(&self) -> usize {
+        8
+    }
+}
+
+/// Writes the index.json of the shards next to them and returns the paths of all the files
+pub fn write_index<T: Packable>(
+    shards: Vec<String>,
+    options: &OutputOptions,
+) -> Result<Vec<String>> {
+    let (names, encodings): (Vec<String>, Vec<String>) =
+        column_encodings(&T::schema(&options.dtypes))?
+            .into_iter()
+            .unzip();
+    let size_limit = options.max_shard_bytes.unwrap_or(DEFAULT_SIZE_LIMIT);
+    let entries = shards
+        .iter()
+        .map(|path| {
+            let mut count = [0; 4];
+            let mut file = File::open(path)?;
+            file.read_exact(&mut count)?;
+            let bytes = file.metadata()?.len();
+            let basename = Path::new(path).file_name().and_then(|name| name.to_str());
+            Ok(json!({
+                "column_encodings": encodings,
+                "column_names": names,
+                "column_sizes": vec![Value::Null; names.len()],
+                "compression": null,
+                "format": "mds",
+                "hashes": [],
+                "raw_data": {"basename": basename, "bytes": bytes, "hashes": {}},
+                "samples": u32::from_le_bytes(count),
+                "size_limit": size_limit,
+                "version": MDS_VERSION,
+                "zip_data": null,
+            }))
+        })
+        .collect::<Result<Vec<Value>>>()?;
+    let folder = Path::new(shards.first().expect("No MDS shards"))
+        .parent()
+        .expect("Invalid file path");
+    let index_path = folder.join(INDEX_FILENAME);
+    let index = json!({"shards": entries, "version": MDS_VERSION});
+    fs::write(&index_path, serde_json::to_string(&index)?)?;
+    eprintln!(
+        "Wrote {} MDS shards to {}, columns are {}",
+        shards.len(),
+        folder.display(),
+        names
+            .iter()
+            .zip(&encodings)
+            .map(|(name, encoding)| format!("{}: {}", name, encoding))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let mut paths = vec![index_path.to_str().expect("Invalid file path").to_string()];
+    paths.extend(shards);
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binpacking::Format;
+    use crate::conversations::TokenizedInput;
+    use crate::writers::fixture::{self, TestFolder};
+
+    fn read_u32(bytes: &[u8], at: usize) -> usize {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn test_ndarray_header() {
+        assert_eq!(ndarray_header(2), vec![1, 4, 2]);
+        assert_eq!(ndarray_header(300), vec![1, 5, 44, 1]);
+    }
+
+    #[test]
+    fn test_mds_shards() {
+        let folder = TestFolder::new("mds_shards");
+        let options = shard_options(&OutputOptions {
+            rows_per_shard: Some(2),
+            ..fixture::options(Format::Mds)
+        });
+        let shards = fixture::write_bins(
+            folder.path(SHARD_FILENAME),
+            &options,
+            vec![vec![1, 2], vec![3], vec![4, 5, 6]],
+            MdsShard::create,
+        );
+        let paths = write_index::<TokenizedInput>(shards, &options).unwrap();
+        assert_eq!(paths.len(), 3);
+
+        let index: Value = serde_json::from_slice(&folder.read(INDEX_FILENAME)).unwrap();
+        let shard = folder.read("shard-00000-of-00002.mds");
+        assert_eq!(index["shards"].as_array().unwrap().len(), 2);
+        assert_eq!(index["shards"][0]["samples"], 2);
+        assert_eq!(index["shards"][1]["samples"], 1);
+        assert_eq!(
+            index["shards"][0]["raw_data"]["basename"],
+            "shard-00000-of-00002.mds"
+        );
+        assert_eq!(
+            index["shards"][0]["column_names"],
+            json!(["input_ids", "labels", "position_ids"])
+        );
+        assert_eq!(
+            index["shards"][0]["column_encodings"],
+            json!(["ndarray:int32", "ndarray:int32", "ndarray:int32"])
+        );
+        assert_eq!(index["shards"][0]["raw_data"]["bytes"], shard.len());
+
+        assert_eq!(read_u32(&shard, 0), 2);
+        // the first sample starts after the count and the 3 offsets
+        let (start, end) = (read_u32(&shard, 4), read_u32(&shard, 8));
+        assert_eq!(start, 16);
+        assert_eq!(read_u32(&shard, 12), shard.len());
+        let sample = &shard[start..end];
+        // column sizes, then the ndarray header and the input ids [1, 2]
+        assert_eq!(
+            (
+                read_u32(sample, 0),
+                read_u32(sample, 4),
+                read_u32(sample, 8)
+            ),
+            (11, 11, 11)
+        );
+        assert_eq!(&sample[12..15], &[1, 4, 2]);
+        assert_eq!(&sample[15..23], &[1, 0, 0, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_mds_shard_bytes() {
+        let folder = TestFolder::new("mds_shard_bytes");
+        // a sample of 2 tokens is its offset and 3 columns of 4 + 11 bytes
+        let max_shard_bytes = 8 + 2 * (4 + 3 * 15);
+        let options = OutputOptions {
+            max_shard_bytes: Some(max_shard_bytes),
+            ..fixture::options(Format::Mds)
+        };
+        let sizes: Vec<usize> = fixture::write_bins(
+            folder.path(SHARD_FILENAME),
+            &options,
+            vec![vec![1, 2], vec![3, 4], vec![5, 6]],
+            MdsShard::create,
+        )
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len() as usize)
+        .collect();
+        assert_eq!(sizes, vec![max_shard_bytes, 8 + 4 + 3 * 15]);
+    }
+}
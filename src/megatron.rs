@@ -0,0 +1,286 @@
+// Handles the Megatron-LM / NeMo indexed dataset, a flat .bin of token ids with an .idx index
+//
+// The .idx layout follows `MMapIndexedDataset` in megatron/core/datasets/indexed_dataset.py:
+// the magic, a u64 version, a u8 dtype code, the u64 sequence and document counts, then the
+// i32 sequence lengths, the i64 byte pointers into the .bin and the i64 document indices.
+use arrow::array::AsArray;
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Schema};
+use arrow::record_batch::RecordBatch;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use crate::binpacking::OutputOptions;
+use crate::error::{Error, Result};
+use crate::writers::{list_item_type, list_parts, sibling_path, value_bytes, BatchWriter};
+
+const INDEX_MAGIC: &[u8] = b"MMIDIDX\x00\x00";
+const INDEX_VERSION: u64 = 1;
+
+/// Dtype code of megatron's `DType` enum
+fn dtype_code(data_type: &DataType) -> Option<u8> {
+    match data_type {
+        DataType::UInt8 => Some(1),
+        DataType::Int8 => Some(2),
+        DataType::Int16 => Some(3),
+        DataType::Int32 => Some(4),
+        DataType::Int64 => Some(5),
+        DataType::Float64 => Some(6),
+        DataType::Float32 => Some(7),
+        DataType::UInt16 => Some(8),
+        _ => None,
+    }
+}
+
+/// Whether megatron can store token ids of this type, it has no unsigned 32 bit dtype
+pub fn supports_dtype(data_type: &DataType) -> bool {
+    dtype_code(data_type).is_some()
+}
+
+/// One .bin/.idx pair
+struct IndexedDataset {
+    path: String,
+    writer: BufWriter<File>,
+    dtype_code: u8,
+    item_size: usize,
+    lengths: Vec<i32>,
+}
+
+impl IndexedDataset {
+    fn create(path: String, data_type: &DataType) -> Result<Self> {
+        let dtype_code = dtype_code(data_type).ok_or_else(|| {
+            Error::Unsupported(format!(
+                "The megatron format does not support the {} dtype",
+                data_type
+            ))
+        })?;
+        Ok(IndexedDataset {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            dtype_code,
+            item_size: data_type.primitive_width().unwrap(),
+            lengths: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8], lengths: &[usize]) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        self.lengths
+            .extend(lengths.iter().map(|length| *length as i32));
+        Ok(())
+    }
+
+    /// Writes the .idx, every sequence is its own document, and returns the paths of the .bin
+    /// and the .idx
+    fn finish(mut self) -> Result<Vec<String>> {
+        self.writer.flush()?;
+        let idx_path = sibling_path(&self.path, "", "idx");
+        let mut idx = BufWriter::new(File::create(&idx_path)?);
+        let count = self.lengths.len() as u64;
+        idx.write_all(INDEX_MAGIC)?;
+        idx.write_all(&INDEX_VERSION.to_le_bytes())?;
+        idx.write_all(&[self.dtype_code])?;
+        idx.write_all(&count.to_le_bytes())?;
+        // the document indices start with 0, so there is one more than the documents
+        idx.write_all(&(count + 1).to_le_bytes())?;
+        for length in &self.lengths {
+            idx.write_all(&length.to_le_bytes())?;
+        }
+        let mut pointer: i64 = 0;
+        for length in &self.lengths {
+            idx.write_all(&pointer.to_le_bytes())?;
+            pointer += *length as i64 * self.item_size as i64;
+        }
+        for document in 0..=count as i64 {
+            idx.write_all(&document.to_le_bytes())?;
+        }
+        idx.flush()?;
+        Ok(vec![self.path, idx_path])
+    }
+}
+
+/// Megatron indexed dataset of the input ids, with an optional uint8 loss mask dataset
+///
+/// Each bin, or each sample with `--no-pack`, is one sequence and one document. The loss mask
+/// is 1 where the label is trained on, aligned with the input ids.
+pub struct MegatronShard {
+    input_ids: IndexedDataset,
+    /// Index of the input_ids column in the schema
+    input_ids_column: usize,
+    loss_mask: Option<IndexedDataset>,
+    /// Labels equal to it are 0 in the loss mask
+    ignore_index: i32,
+}
+
+impl MegatronShard {
+    pub fn create(path: &str, schema: &Arc<Schema>, options: &OutputOptions) -> Result<Self> {
+        let (Ok(input_ids), Ok(_)) = (
+            schema.field_with_name("input_ids"),
+            schema.field_with_name("labels"),
+        ) else {
+            return Err(Error::Unsupported(
+                "The megatron format needs the input_ids and labels columns, of the sft mode"
+                    .to_string(),
+            ));
+        };
+        let item_type = list_item_type(input_ids.data_type())?;
+        Ok(MegatronShard {
+            input_ids: IndexedDataset::create(path.to_string(), item_type)?,
+            input_ids_column: schema.index_of("input_ids")?,
+            ignore_index: options.ignore_index,
+            loss_mask: options
+                .loss_mask
+                .then(|| {
+                    let path = sibling_path(path, "_loss_mask", "bin");
+                    IndexedDataset::create(path, &DataType::UInt8)
+                })
+                .transpose()?,
+        })
+    }
+}
+
+impl BatchWriter for MegatronShard {
+    fn write_batch(&mut self, batch: RecordBatch) -> Result<()> {
+        let (values, lengths) = list_parts(batch.column_by_name("input_ids").unwrap())?;
+        self.input_ids.write(&value_bytes(&values), &lengths)?;
+        if let Some(loss_mask) = self.loss_mask.as_mut() {
+            let (labels, lengths) = list_parts(batch.column_by_name("labels").unwrap())?;
+            let labels = cast(&labels, &DataType::Int32)?;
+            let ignore_index = self.ignore_index;
+            let mask: Vec<u8> = labels
+                .as_primitive::<arrow::datatypes::Int32Type>()
+                .values()
+                .iter()
+                .map(|label| (*label != ignore_index) as u8)
+                .collect();
+            loss_mask.write(&mask, &lengths)?;
+        }
+        Ok(())
+    }
+    fn finish(self) -> Result<Vec<String>> {
+        let mut paths = self.input_ids.finish()?;
+        if let Some(loss_mask) = self.loss_mask {
+            paths.extend(loss_mask.finish()?);
+        }
+        Ok(paths)
+    }
+    /// The input ids, and a byte per token of the loss mask
+    fn bin_bytes(&self, columns: &[(usize, usize)]) -> usize {
+        let (length, bytes) = columns[self.input_ids_column];
+        match self.loss_mask {
+            Some(_) => bytes + length,
+            None => bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binpacking::{Dtype, Format, Packable, TokenDtypes};
+    use crate::conversations::TokenizedInput;
+    use crate::writers::fixture::{self, TestFolder};
+
+    fn read_u64(bytes: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_megatron_shard() {
+        let folder = TestFolder::new("megatron_shard");
+        let options = OutputOptions {
+            dtypes: TokenDtypes::new(Dtype::Auto, 100, 8, 1, -100).unwrap(),
+            loss_mask: true,
+            ..fixture::options(Format::Megatron)
+        };
+        fixture::write_bins(
+            folder.path("file.bin"),
+            &options,
+            vec![vec![1, 2, 3], vec![4, 5]],
+            MegatronShard::create,
+        );
+
+        let bin = folder.read("file.bin");
+        let idx = folder.read("file.idx");
+        let mask = folder.read("file_loss_mask.bin");
+        let mask_idx = folder.read("file_loss_mask.idx");
+
+        // uint16 ids
+        assert_eq!(bin, vec![1, 0, 2, 0, 3, 0, 4, 0, 5, 0]);
+        assert_eq!(&idx[..9], INDEX_MAGIC);
+        assert_eq!(read_u64(&idx, 9), 1);
+        assert_eq!(idx[17], 8);
+        assert_eq!(read_u64(&idx, 18), 2);
+        assert_eq!(read_u64(&idx, 26), 3);
+        // lengths, pointers and document indices
+        let lengths: Vec<i32> = idx[34..42]
+            .chunks(4)
+            .map(|b| i32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(lengths, vec![3, 2]);
+        assert_eq!(read_u64(&idx, 42), 0);
+        assert_eq!(read_u64(&idx, 50), 6);
+        assert_eq!(
+            (0..3)
+                .map(|i| read_u64(&idx, 58 + i * 8))
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(idx.len(), 82);
+
+        assert_eq!(mask, vec![0, 1, 1, 0, 1]);
+        assert_eq!(mask_idx[17], 1);
+        assert_eq!(read_u64(&mask_idx, 50), 3);
+    }
+
+    #[test]
+    fn test_rows_per_shard() {
+        let folder = TestFolder::new("megatron_rows_per_shard");
+        let options = OutputOptions {
+            loss_mask: true,
+            rows_per_shard: Some(2),
+            ..fixture::options(Format::Megatron)
+        };
+        let paths = fixture::write_bins(
+            folder.path("file.bin"),
+            &options,
+            vec![vec![1, 2, 3], vec![4, 5], vec![6]],
+            MegatronShard::create,
+        );
+        assert_eq!(paths.len(), 8);
+        assert_eq!(
+            folder.file_names(),
+            vec![
+                "file-00000-of-00002.bin",
+                "file-00000-of-00002.idx",
+                "file-00000-of-00002_loss_mask.bin",
+                "file-00000-of-00002_loss_mask.idx",
+                "file-00001-of-00002.bin",
+                "file-00001-of-00002.idx",
+                "file-00001-of-00002_loss_mask.bin",
+                "file-00001-of-00002_loss_mask.idx",
+            ]
+        );
+        // the second shard only holds the last sequence
+        let idx = folder.read("file-00001-of-00002.idx");
+        assert_eq!(read_u64(&idx, 18), 1);
+    }
+
+    #[test]
+    fn test_unsupported_schema() {
+        let folder = TestFolder::new("megatron_unsupported");
+        let path = folder.path("file.bin");
+        let options = OutputOptions::default();
+        let schema = Arc::new(crate::preference::TokenizedPair::schema(&options.dtypes));
+        let pairs = MegatronShard::create(&path, &schema, &options);
+        assert!(matches!(pairs, Err(Error::Unsupported(_))));
+        let options = OutputOptions {
+            dtypes: TokenDtypes::new(Dtype::Uint32, 100, 8, 1, -100).unwrap(),
+            ..OutputOptions::default()
+        };
+        let schema = Arc::new(TokenizedInput::schema(&options.dtypes));
+        let uint32 = MegatronShard::create(&path, &schema, &options);
+        assert!(matches!(uint32, Err(Error::Unsupported(_))));
+    }
+}
@@ -0,0 +1,384 @@
+// Handles the NumPy .npy output, one array per column
+//
+// The shape is only known at the end, so a fixed size header is written first and
+// rewritten with the final shape when the file is finished.
+use arrow::array::{ArrayRef, Int32Array};
+use arrow::compute::{cast_with_options, CastOptions};
+use arrow::datatypes::{DataType, Schema};
+use arrow::record_batch::RecordBatch;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use crate::binpacking::OutputOptions;
+use crate::error::{Error, Result};
+use crate::writers::{list_item_type, list_parts, sibling_path, value_bytes, BatchWriter};
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+/// Size of the magic, version, header length and the header, a multiple of 64
+pub(crate) const NPY_HEADER_SIZE: usize = 128;
+
+/// Layout of the npy columns
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NpyLayout {
+    /// 2-D arrays padded to the max length
+    #[default]
+    Padded,
+    /// 1-D arrays with an offsets file
+    Flat,
+}
+
+/// Type string of the `descr` field
+pub(crate) fn descr(data_type: &DataType) -> Result<&'static str> {
+    Ok(match data_type {
+        DataType::UInt8 => "|u1",
+        DataType::Int8 => "|i1",
+        DataType::UInt16 => "<u2",
+        DataType::Int16 => "<i2",
+        DataType::UInt32 => "<u4",
+        DataType::Int32 => "<i4",
+        DataType::UInt64 => "<u8",
+        DataType::Int64 => "<i8",
+        DataType::Float32 => "<f4",
+        DataType::Float64 => "<f8",
+        _ => {
+            return Err(Error::Unsupported(format!(
+                "No npy dtype for {}",
+                data_type
+            )))
+        }
+    })
+}
+
+/// Header of a version 1.0 file, padded with spaces to `NPY_HEADER_SIZE`
+pub(crate) fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [length] => format!("({},)", length),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|dim| dim.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    let header_length = NPY_HEADER_SIZE - NPY_MAGIC.len() - 2;
+    let mut header = NPY_MAGIC.to_vec();
+    header.extend((header_length as u16).to_le_bytes());
+    header.extend(format!("{:<width$}\n", dict, width = header_length - 1).bytes());
+    header
+}
+
+/// A .npy file that values are appended to
+struct NpyArray {
+    path: String,
+    writer: BufWriter<File>,
+    descr: &'static str,
+    len: usize,
+}
+
+impl NpyArray {
+    fn create(path: &str, data_type: &DataType) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let descr = descr(data_type)?;
+        writer.write_all(&npy_header(descr, &[0]))?;
+        Ok(NpyArray {
+            path: path.to_string(),
+            writer,
+            descr,
+            len: 0,
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8], len: usize) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        self.len += len;
+        Ok(())
+    }
+
+    /// Rewrites the header with the final shape, `width` makes it a 2-D array of rows, and
+    /// returns the path of the file
+    fn finish(self, width: Option<usize>) -> Result<String> {
+        let shape = match width {
+            Some(width) => vec![self.len.checked_div(width).unwrap_or(0), width],
+            None => vec![self.len],
+        };
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&npy_header(self.descr, &shape))?;
+        Ok(self.path)
+    }
+}
+
+/// A list column of the schema and its .npy file
+struct NpyColumn {
+    name: String,
+    item_type: DataType,
+    /// Bytes of the padding value, in the type of the column
+    pad: Vec<u8>,
+    array: NpyArray,
+    /// Lengths of the rows for the offsets in the flat layout
+    lengths: Vec<usize>,
+}
+
+/// Padding value of a column, labels are padded with the ignore index so they are ignored
+/// in the loss
+fn pad_value(name: &str, options: &OutputOptions) -> i32 {
+    if name.ends_with("labels") {
+        options.ignore_index
+    } else if name.ends_with("input_ids") {
+        options.pad_id
+    } else {
+        0
+    }
+}
+
+/// One .npy file per column, eg. out/file_input_ids.npy and out/file_labels.npy
+///
+/// The `padded` layout writes 2-D arrays with a row per bin, padded to `max_length`. The
+/// `flat` layout writes the tokens of all the bins as 1-D arrays, with the row boundaries in
+/// an int64 offsets file, eg. out/file_offsets.npy, for every group of columns.
+pub struct NpyShard {
+    path: String,
+    columns: Vec<NpyColumn>,
+    width: Option<usize>,
+}
+
+impl NpyShard {
+    pub fn create(path: &str, schema: &Arc<Schema>, options: &OutputOptions) -> Result<Self> {
+        let width = match options.npy_layout {
+            NpyLayout::Padded => Some(options.max_length as usize),
+            NpyLayout::Flat => None,
+        };
+        // check all the columns before creating any file
+        for field in schema.fields() {
+            descr(list_item_type(field.data_type())?)?;
+        }
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field| {
+                let item_type = list_item_type(field.data_type())?.clone();
+                let pad: ArrayRef =
+                    Arc::new(Int32Array::from(vec![pad_value(field.name(), options)]));
+                let cast_options = CastOptions {
+                    safe: false,
+                    ..Default::default()
+                };
+                let pad = cast_with_options(&pad, &item_type, &cast_options)?;
+                Ok(NpyColumn {
+                    name: field.name().clone(),
+                    array: NpyArray::create(
+                        &sibling_path(path, &format!("_{}", field.name()), "npy"),
+                        &item_type,
+                    )?,
+                    pad: value_bytes(&pad).to_vec(),
+                    item_type,
+                    lengths: Vec::new(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(NpyShard {
+            path: path.to_string(),
+            columns,
+            width,
+        })
+    }
+
+    /// Writes the offsets of the columns ending in input_ids, the other columns with the same
+    /// prefix have the same row lengths
+    fn write_offsets(&self) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        for column in &self.columns {
+            let Some(prefix) = column.name.strip_suffix("input_ids") else {
+                continue;
+            };
+            let path = sibling_path(&self.path, &format!("_{}offsets", prefix), "npy");
+            let mut offsets = NpyArray::create(&path, &DataType::Int64)?;
+            let mut offset: i64 = 0;
+            offsets.write(&offset.to_le_bytes(), 1)?;
+            for length in &column.lengths {
+                offset += *length as i64;
+                offsets.write(&offset.to_le_bytes(), 1)?;
+            }
+            paths.push(offsets.finish(None)?);
+        }
+        Ok(paths)
+    }
+}
+
+impl BatchWriter for NpyShard {
+    fn write_batch(&mut self, batch: RecordBatch) -> Result<()> {
+        for (column, values) in self.columns.iter_mut().zip(batch.columns()) {
+            let (values, lengths) = list_parts(values)?;
+            let bytes = value_bytes(&values);
+            match self.width {
+                Some(width) => {
+                    let item_size = column.item_type.primitive_width().unwrap();
+                    let mut start = 0;
+                    for length in &lengths {
+                        let end = start + length * item_size;
+                        let mut row = bytes[start..end].to_vec();
+                        row.extend(column.pad.repeat(width - length));
+                        column.array.write(&row, width)?;
+                        start = end;
+                    }
+                }
+                None => column.array.write(&bytes, values.len())?,
+            }
+            column.lengths.extend(lengths);
+        }
+        Ok(())
+    }
+    fn finish(self) -> Result<Vec<String>> {
+        let offsets = match self.width {
+            Some(_) => Vec::new(),
+            None => self.write_offsets()?,
+        };
+        let mut paths = self
+            .columns
+            .into_iter()
+            .map(|column| column.array.finish(self.width))
+            .collect::<Result<Vec<_>>>()?;
+        paths.extend(offsets);
+        Ok(paths)
+    }
+    /// Every row is padded to the width in the padded layout
+    fn bin_bytes(&self, columns: &[(usize, usize)]) -> usize {
+        match self.width {
+            Some(width) => self
+                .columns
+                .iter()
+                .map(|column| width * column.item_type.primitive_width().unwrap())
+                .sum(),
+            None => columns.iter().map(|(_, bytes)| bytes).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binpacking::{Dtype, Format, Packable, TokenDtypes};
+    use crate::conversations::TokenizedInput;
+    use crate::writers::fixture::{self, TestFolder};
+
+    fn write_npy(name: &str, layout: NpyLayout) -> TestFolder {
+        let folder = TestFolder::new(name);
+        let options = OutputOptions {
+            max_length: 4,
+            dtypes: TokenDtypes::new(Dtype::Auto, 100, 4, 1, -100).unwrap(),
+            npy_layout: layout,
+            pad_id: 7,
+            ..fixture::options(Format::Npy)
+        };
+        fixture::write_bins(
+            folder.path("file.npy"),
+            &options,
+            vec![vec![1, 2, 3], vec![4, 5]],
+            NpyShard::create,
+        );
+        folder
+    }
+
+    #[test]
+    fn test_npy_header() {
+        let header = npy_header("<u2", &[2, 4]);
+        assert_eq!(header.len(), NPY_HEADER_SIZE);
+        assert!(header.ends_with(b"\n"));
+        let dict = String::from_utf8(header[10..].to_vec()).unwrap();
+        assert_eq!(
+            dict.trim_end(),
+            "{'descr': '<u2', 'fortran_order': False, 'shape': (2, 4), }"
+        );
+    }
+
+    #[test]
+    fn test_padded() {
+        let folder = write_npy("npy_padded", NpyLayout::Padded);
+        let input_ids = folder.read("file_input_ids.npy");
+        let labels = folder.read("file_labels.npy");
+        assert_eq!(
+            &input_ids[..NPY_HEADER_SIZE],
+            &npy_header("<u2", &[2, 4])[..]
+        );
+        assert_eq!(
+            &input_ids[NPY_HEADER_SIZE..],
+            &[1, 0, 2, 0, 3, 0, 7, 0, 4, 0, 5, 0, 7, 0, 7, 0]
+        );
+        let labels: Vec<i16> = labels[NPY_HEADER_SIZE..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(labels, vec![-100, 2, 3, -100, -100, 5, -100, -100]);
+    }
+
+    #[test]
+    fn test_flat() {
+        let folder = write_npy("npy_flat", NpyLayout::Flat);
+        let input_ids = folder.read("file_input_ids.npy");
+        let offsets = folder.read("file_offsets.npy");
+        assert_eq!(&input_ids[..NPY_HEADER_SIZE], &npy_header("<u2", &[5])[..]);
+        assert_eq!(
+            &input_ids[NPY_HEADER_SIZE..],
+            &[1, 0, 2, 0, 3, 0, 4, 0, 5, 0]
+        );
+        let offsets: Vec<i64> = offsets[NPY_HEADER_SIZE..]
+            .chunks(8)
+            .map(|b| i64::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(offsets, vec![0, 3, 5]);
+    }
+
+    #[test]
+    fn test_rows_per_shard() {
+        let folder = TestFolder::new("npy_rows_per_shard");
+        let options = OutputOptions {
+            npy_layout: NpyLayout::Flat,
+            rows_per_shard: Some(2),
+            ..fixture::options(Format::Npy)
+        };
+        let paths = fixture::write_bins(
+            folder.path("file.npy"),
+            &options,
+            vec![vec![1, 2, 3], vec![4, 5], vec![6]],
+            NpyShard::create,
+        );
+        assert_eq!(paths.len(), 8);
+        assert_eq!(
+            folder.file_names(),
+            vec![
+                "file-00000-of-00002_input_ids.npy",
+                "file-00000-of-00002_labels.npy",
+                "file-00000-of-00002_offsets.npy",
+                "file-00000-of-00002_position_ids.npy",
+                "file-00001-of-00002_input_ids.npy",
+                "file-00001-of-00002_labels.npy",
+                "file-00001-of-00002_offsets.npy",
+                "file-00001-of-00002_position_ids.npy",
+            ]
+        );
+        // the second shard only holds the last row
+        let offsets: Vec<i64> = folder.read("file-00001-of-00002_offsets.npy")[NPY_HEADER_SIZE..]
+            .chunks(8)
+            .map(|b| i64::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(offsets, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_source_ids_unsupported() {
+        let folder = TestFolder::new("npy_source_ids");
+        let mut options = OutputOptions::default();
+        options.dtypes.source_ids = true;
+        let schema = Arc::new(TokenizedInput::schema(&options.dtypes));
+        let shard = NpyShard::create(&folder.path("file.npy"), &schema, &options);
+        assert!(matches!(shard, Err(Error::Unsupported(_))));
+        assert!(folder.file_names().is_empty());
+    }
+}
@@ -0,0 +1,437 @@
+// Handles the tokenizer and chat template shared by the workers of a run
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokenizers::{Encoding, Tokenizer};
+
+use crate::conversations::{LabelPolicy, Rendered, Tokenize};
+use crate::error::{Error, Result};
+use crate::template::ChatTemplate;
+
+/// Loads a tokenizer from a tokenizer.json file, or from huggingface with `<org>/<name>`
+pub fn load_tokenizer(tokenizer_name: &str) -> Result<Tokenizer> {
+    if tokenizer_name.ends_with(".json") {
+        eprintln!("Loading tokenizer from file: {}", tokenizer_name);
+        Ok(Tokenizer::from_file(tokenizer_name)?)
+    } else {
+        eprintln!("Loading tokenizer: {}", tokenizer_name);
+        Ok(Tokenizer::from_pretrained(tokenizer_name, None)?)
+    }
+}
+
+/// The tokenizer and chat template used to tokenize the records
+///
+/// Cloning is cheap, the tokenizer is shared between the clones. Each pipeline holds its own
+/// tokenizer, so files can be processed with different tokenizers in the same process.
+#[derive(Clone)]
+pub struct Pipeline {
+    tokenizer: Arc<Tokenizer>,
+    pub template: ChatTemplate,
+    /// Let the post-processor of the tokenizer add its special tokens, eg. a BOS, on top of
+    /// the ones in the chat template
+    pub add_special_tokens: bool,
+    /// Remove a doubled BOS at the start or EOS at the end of the tokens, when both the
+    /// template and the tokenizer add them
+    pub dedup_special_tokens: bool,
+    /// Token appended to every sample, so the documents stay apart once packed
+    pub separator_id: Option<u32>,
+    /// Train on the separator, otherwise its label is the ignore index
+    pub train_separator: bool,
+    /// Shift the labels, so `labels[i]` is the label of `input_ids[i + 1]`, for trainers that
+    /// expect them shifted
+    pub shift_labels: bool,
+    /// Ignore index, the roles that are trained on and the loss weights
+    pub labels: LabelPolicy,
+    /// Keep where every record comes from, its `id` field or its line
+    pub source_ids: bool,
+    /// Number the documents of every bin, for a per-token document ids column
+    pub document_ids: bool,
+    bos_id: Option<u32>,
+    eos_id: Option<u32>,
+    /// Set once the doubled special tokens have been reported
+    warned: Arc<AtomicBool>,
+}
+
+/// Number of extra BOS tokens at the start and extra EOS tokens at the end of the ids
+fn duplicated_special_tokens(
+    ids: &[u32],
+    bos_id: Option<u32>,
+    eos_id: Option<u32>,
+) -> (usize, usize) {
+    let repeats = |id: Option<u32>, ids: &mut dyn Iterator<Item = &u32>| {
+        id.map_or(0, |id| {
+            ids.take_while(|x| **x == id).count().saturating_sub(1)
+        })
+    };
+    let leading = repeats(bos_id, &mut ids.iter());
+    let trailing = repeats(eos_id, &mut ids.iter().rev());
+    // a text of only special tokens keeps one of them
+    (leading, trailing.min(ids.len().saturating_sub(leading + 1)))
+}
+
+impl Pipeline {
+    pub fn new(tokenizer: Tokenizer, template: ChatTemplate) -> Self {
+        let token_id = |token: Option<&str>| token.and_then(|token| tokenizer.token_to_id(token));
+        let bos_id = token_id(template.bos_token());
+        let eos_id = token_id(template.eos_token());
+        Pipeline {
+            tokenizer: Arc::new(tokenizer),
+            template,
+            add_special_tokens: false,
+            dedup_special_tokens: false,
+            separator_id: None,
+            train_separator: false,
+            shift_labels: false,
+            labels: LabelPolicy::default(),
+            source_ids: false,
+            document_ids: false,
+            bos_id,
+            eos_id,
+            warned: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Id of a token, or the token given as its id
+    pub fn token_id(&self, token: &str) -> Result<u32> {
+        self.tokenizer
+            .token_to_id(token)
+            .or_else(|| {
+                token
+                    .parse()
+                    .ok()
+                    .filter(|id| (*id as usize) < self.vocab_size())
+            })
+            .ok_or_else(|| Error::UnknownToken(token.to_string()))
+    }
+
+    /// Encodes a single text, with the special tokens of the tokenizer if `add_special_tokens`
+    pub fn encode(&self, content: &str) -> Result<Encoding> {
+        Ok(self.tokenizer.encode(content, self.add_special_tokens)?)
+    }
+
+    /// Checks the ids for a doubled BOS or EOS, and removes the extra ones if
+    /// `dedup_special_tokens`, otherwise the first doubled text is reported
+    fn check_special_tokens(&self, ids: &[u32]) -> std::ops::Range<usize> {
+        let (leading, trailing) = duplicated_special_tokens(ids, self.bos_id, self.eos_id);
+        if leading + trailing == 0 {
+            return 0..ids.len();
+        }
+        if self.dedup_special_tokens {
+            return leading..ids.len() - trailing;
+        }
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "Warning: found {} extra BOS and {} extra EOS tokens in a text, the chat template and the tokenizer both add them. Remove them with --dedup-special-tokens",
+                leading, trailing
+            );
+        }
+        0..ids.len()
+    }
+
+    /// Renders and tokenizes the records, jsonl lines of the record type, in their order
+    ///
+    /// The records are rendered in parallel and all the texts are encoded in one batch, which
+    /// the tokenizer parallelises on its own.
+    ///
+    /// ```no_run
+    /// use collate::{load_tokenizer, ChatTemplate, Pipeline, TokenizedInput};
+    ///
+    /// let template = ChatTemplate::new(
+    ///     "{% for message in messages %}{{ message.content }}{% endfor %}".to_string(),
+    ///     None,
+    ///     None,
+    /// )?;
+    /// let pipeline = Pipeline::new(load_tokenizer("tokenizer.json")?, template);
+    /// let records = [r#"{"conversations": [{"role": "user", "content": "Hi"}]}"#];
+    /// let inputs: Vec<TokenizedInput> = pipeline.tokenize(&records)?;
+    /// # Ok::<(), collate::Error>(())
+    /// ```
+    pub fn tokenize<T: Tokenize, R: AsRef<str> + Sync>(&self, records: &[R]) -> Result<Vec<T>> {
+        self.tokenize_with_sources(records, |index| index.to_string())
+    }
+
+    /// Same as `tokenize`, with `source` giving the source id of the record at an index when
+    /// it has no `id` field, eg. its file and line
+    pub fn tokenize_with_sources<T: Tokenize, R: AsRef<str> + Sync>(
+        &self,
+        records: &[R],
+        source: impl Fn(usize) -> String,
+    ) -> Result<Vec<T>> {
+        let rendered = records
+            .par_iter()
+            .map(|record| T::render(record.as_ref(), &self.template, &self.labels))
+            .collect::<Result<Vec<Rendered>>>()?;
+        let counts: Vec<usize> = rendered.iter().map(|record| record.texts.len()).collect();
+        let mut texts = Vec::with_capacity(counts.iter().sum());
+        let mut labels = Vec::with_capacity(rendered.len());
+        let mut sources = Vec::with_capacity(rendered.len());
+        for (index, record) in rendered.into_iter().enumerate() {
+            texts.extend(record.texts);
+            labels.push(record.labels);
+            let id = record.id;
+            sources.push(self.source_ids.then(|| id.unwrap_or_else(|| source(index))));
+        }
+        // convert the ids to i32 for arrow
+        let mut ids = self
+            .tokenizer
+            .encode_batch(texts, self.add_special_tokens)?
+            .into_iter()
+            .map(|encoding| {
+                let ids = encoding.get_ids();
+                ids[self.check_special_tokens(ids)]
+                    .iter()
+                    .map(|id| *id as i32)
+                    .collect()
+            });
+        let separator = self.separator_id.map(|id| {
+            let label = if self.train_separator {
+                id as i32
+            } else {
+                self.labels.ignore_index
+            };
+            (id as i32, label)
+        });
+        counts
+            .into_iter()
+            .zip(labels)
+            .zip(sources)
+            .map(|((count, labels), source)| {
+                let record_ids = ids.by_ref().take(count).collect();
+                let mut record = T::from_ids(record_ids, &labels, &self.labels)?;
+                if let Some(source) = source {
+                    record.set_source(source);
+                }
+                if self.document_ids {
+                    record.set_document_ids();
+                }
+                if let Some((id, label)) = separator {
+                    record.push_separator(id, label);
+                }
+                // after the separator, so the last token of the document predicts it
+                if self.shift_labels {
+                    record.shift_labels(self.labels.ignore_index);
+                }
+                Ok(record)
+            })
+            .collect()
+    }
+
+    /// Number of token ids of the tokenizer, the largest id plus one, including the added
+    /// tokens
+    pub fn vocab_size(&self) -> usize {
+        self.tokenizer
+            .get_vocab(true)
+            .values()
+            .max()
+            .map_or(0, |id| *id as usize + 1)
+    }
+}
+
+/// A small word level tokenizer for tests, every word of `FIXTURE_WORDS` is a token
+#[cfg(test)]
+pub(crate) fn fixture_tokenizer() -> Tokenizer {
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::AddedToken;
+
+    const FIXTURE_WORDS: [&str; 8] = [
+        "<unk>",
+        "user",
+        "assistant",
+        "hi",
+        "hello",
+        "there",
+        "bye",
+        "!",
+    ];
+    let vocab: HashMap<String, u32> = FIXTURE_WORDS
+        .iter()
+        .enumerate()
+        .map(|(id, word)| (word.to_string(), id as u32))
+        .collect();
+    let model = WordLevel::builder()
+        .vocab(vocab)
+        .unk_token("<unk>".to_string())
+        .build()
+        .unwrap();
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+    tokenizer.add_special_tokens(&[
+        AddedToken::from("<s>", true),
+        AddedToken::from("</s>", true),
+    ]);
+    tokenizer
+}
+
+/// A pipeline of the fixture tokenizer with a simple chat template
+#[cfg(test)]
+pub(crate) fn fixture_pipeline() -> Pipeline {
+    let template = ChatTemplate::new(
+        "{{ bos_token }}{% for message in messages %}{{ message.role }} {{ message.content }} {{ eos_token }} {% endfor %}{% if add_generation_prompt %}assistant {% endif %}".to_string(),
+        Some("<s>".to_string()),
+        Some("</s>".to_string()),
+    )
+    .unwrap();
+    Pipeline::new(fixture_tokenizer(), template)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversations::TokenizedInput;
+
+    #[test]
+    fn test_fixture_pipeline() {
+        let pipeline = fixture_pipeline();
+        // the special tokens are added after the words
+        assert_eq!(pipeline.vocab_size(), 10);
+        let encoding = pipeline.encode("<s>user hi !</s>").unwrap();
+        assert_eq!(encoding.get_ids(), &[8, 1, 3, 7, 9]);
+    }
+
+    #[test]
+    fn test_duplicated_special_tokens() {
+        assert_eq!(
+            duplicated_special_tokens(&[8, 8, 1, 9, 9, 9], Some(8), Some(9)),
+            (1, 2)
+        );
+        assert_eq!(
+            duplicated_special_tokens(&[8, 1, 9], Some(8), Some(9)),
+            (0, 0)
+        );
+        assert_eq!(duplicated_special_tokens(&[8, 8], None, None), (0, 0));
+        // the same token as BOS and EOS is kept once
+        assert_eq!(
+            duplicated_special_tokens(&[8, 8, 8], Some(8), Some(8)),
+            (2, 0)
+        );
+    }
+
+    #[test]
+    fn test_add_special_tokens() {
+        use tokenizers::processors::template::TemplateProcessing;
+
+        let mut tokenizer = fixture_tokenizer();
+        let post_processor = TemplateProcessing::builder()
+            .try_single("<s> $A </s>")
+            .unwrap()
+            .special_tokens(vec![("<s>", 8), ("</s>", 9)])
+            .build()
+            .unwrap();
+        tokenizer.with_post_processor(Some(post_processor));
+        let mut pipeline = Pipeline::new(tokenizer, fixture_pipeline().template);
+        let records = [r#"{"conversations": [{"role": "user", "content": "hi"}]}"#];
+        let inputs: Vec<TokenizedInput> = pipeline.tokenize(&records).unwrap();
+        assert_eq!(inputs[0].input_ids, vec![8, 1, 3, 9]);
+
+        pipeline.add_special_tokens = true;
+        let inputs: Vec<TokenizedInput> = pipeline.tokenize(&records).unwrap();
+        assert_eq!(inputs[0].input_ids, vec![8, 8, 1, 3, 9, 9]);
+
+        pipeline.dedup_special_tokens = true;
+        let inputs: Vec<TokenizedInput> = pipeline.tokenize(&records).unwrap();
+        assert_eq!(inputs[0].input_ids, vec![8, 1, 3, 9]);
+    }
+
+    #[test]
+    fn test_separator() {
+        let mut pipeline = fixture_pipeline();
+        assert_eq!(pipeline.token_id("!").unwrap(), 7);
+        assert_eq!(pipeline.token_id("9").unwrap(), 9);
+        assert!(pipeline.token_id("10").is_err());
+        // the branches end with the EOS of the template, so another token is the separator
+        let records = [r#"{"prompt": "hi", "chosen": "hello", "rejected": "bye"}"#];
+        pipeline.separator_id = Some(7);
+        let pairs: Vec<crate::TokenizedPair> = pipeline.tokenize(&records).unwrap();
+        assert_eq!(pairs[0].chosen.input_ids.last(), Some(&7));
+        assert_eq!(pairs[0].chosen.labels.last(), Some(&-100));
+        assert_eq!(pairs[0].length, pairs[0].chosen.length);
+
+        pipeline.train_separator = true;
+        let pairs: Vec<crate::TokenizedPair> = pipeline.tokenize(&records).unwrap();
+        assert_eq!(pairs[0].rejected.labels.last(), Some(&7));
+    }
+
+    #[test]
+    fn test_label_policy() {
+        let mut pipeline = fixture_pipeline();
+        let records = [concat!(
+            r#"{"conversations": [{"role": "user", "content": "hi"}, {"role": "assistant", "content": "hello"}, "#,
+            r#"{"role": "user", "content": "bye"}, {"role": "assistant", "content": "there"}]}"#
+        )];
+        pipeline.labels.train_roles = Some(vec!["assistant".to_string()]);
+        let inputs: Vec<TokenizedInput> = pipeline.tokenize(&records).unwrap();
+        assert_eq!(
+            inputs[0].input_ids,
+            vec![8, 1, 3, 9, 2, 4, 9, 1, 6, 9, 2, 5, 9]
+        );
+        // the assistant headers are masked with the user messages
+        let i = -100;
+        assert_eq!(
+            inputs[0].labels,
+            vec![i, i, i, i, i, 4, 9, i, i, i, i, 5, 9]
+        );
+
+        pipeline.labels = LabelPolicy {
+            ignore_index: -1,
+            train_roles: None,
+            last_assistant_only: true,
+            ..LabelPolicy::default()
+        };
+        let inputs: Vec<TokenizedInput> = pipeline.tokenize(&records).unwrap();
+        let i = -1;
+        assert_eq!(
+            inputs[0].labels,
+            vec![i, i, i, i, i, i, i, i, i, i, i, 5, 9]
+        );
+    }
+
+    #[test]
+    fn test_loss_weights() {
+        let mut pipeline = fixture_pipeline();
+        let records = [
+            r#"{"conversations": [{"role": "user", "content": "hi", "weight": 2.0}, {"role": "assistant", "content": "hello"}], "weight": 0.5}"#,
+            r#"{"conversations": [{"role": "user", "content": "bye"}], "weight": 0.25}"#,
+        ];
+        let inputs: Vec<TokenizedInput> = pipeline.tokenize(&records).unwrap();
+        assert!(inputs[0].loss_weights.is_empty());
+
+        pipeline.labels.loss_weights = true;
+        let inputs: Vec<TokenizedInput> = pipeline.tokenize(&records).unwrap();
+        assert_eq!(inputs[0].input_ids, vec![8, 1, 3, 9, 2, 4, 9]);
+        assert_eq!(inputs[0].labels, vec![-100, 1, 3, 9, 2, 4, 9]);
+        // the message weights are multiplied by the record weight
+        assert_eq!(
+            inputs[0].loss_weights,
+            vec![0.5, 1.0, 1.0, 1.0, 1.0, 0.5, 0.5]
+        );
+        assert_eq!(inputs[1].loss_weights, vec![0.25; 4]);
+    }
+
+    #[test]
+    fn test_source_ids() {
+        let mut pipeline = fixture_pipeline();
+        pipeline.source_ids = true;
+        pipeline.document_ids = true;
+        let records = [
+            r#"{"conversations": [{"role": "user", "content": "hi"}], "id": "first"}"#,
+            r#"{"conversations": [{"role": "user", "content": "bye"}], "id": 7}"#,
+            r#"{"conversations": [{"role": "user", "content": "hello"}]}"#,
+        ];
+        let inputs: Vec<TokenizedInput> = pipeline
+            .tokenize_with_sources(&records, |index| format!("file.jsonl:{}", index + 11))
+            .unwrap();
+        let sources: Vec<&str> = inputs.iter().map(|i| i.source_ids[0].as_str()).collect();
+        assert_eq!(sources, vec!["first", "7", "file.jsonl:13"]);
+        assert_eq!(inputs[0].document_ids, vec![0; 4]);
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let pipeline = fixture_pipeline();
+        let records = [r#"{"conversations": []}"#, "not json"];
+        let result = pipeline.tokenize::<TokenizedInput, _>(&records);
+        assert!(matches!(result, Err(crate::Error::Record(_))));
+    }
+}
@@ -0,0 +1,259 @@
+// Handles preference pairs for DPO/ORPO style training
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::cmp::Ordering;
+
+use crate::conversations::{
+    common_prefix, source_id, LabelPolicy, RecordLabels, Rendered, Tokenize, TokenizedInput,
+};
+use crate::error;
+use crate::template::{self, TextMessage};
+
+/// A branch of a preference record, either a list of messages or a plain string
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Turns {
+    Messages(Vec<TextMessage>),
+    Text(String),
+}
+
+impl Turns {
+    /// Plain strings are treated as a single message with the given role
+    fn into_messages(self, role: &str) -> Vec<TextMessage> {
+        match self {
+            Turns::Messages(messages) => messages,
+            Turns::Text(content) => vec![TextMessage {
+                role: role.to_string(),
+                content,
+                weight: None,
+            }],
+        }
+    }
+}
+
+/// A record with a shared prompt and a chosen and rejected response
+///
+/// ```json
+/// {"prompt": [{"role": "user", "content": "Hi"}], "chosen": [{"role": "assistant", "content": "Hello!"}], "rejected": "Go away"}
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct PreferenceRecord {
+    prompt: Turns,
+    chosen: Turns,
+    rejected: Turns,
+    /// Source id of the record, with `--source-ids`
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Default)]
+pub struct TokenizedPair {
+    pub chosen: TokenizedInput,
+    pub rejected: TokenizedInput,
+    /// Length of the longest branch
+    pub length: i32,
+}
+
+impl Ord for TokenizedPair {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.length.cmp(&other.length)
+    }
+}
+
+impl PartialOrd for TokenizedPair {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for TokenizedPair {
+    fn eq(&self, other: &Self) -> bool {
+        self.length == other.length
+    }
+}
+
+impl Eq for TokenizedPair {}
+
+// Written out flat, with the branch name as prefix of each column, and the source ids of the
+// chosen branch once for both
+impl Serialize for TokenizedPair {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("TokenizedPair", 10)?;
+        state.serialize_field("chosen_input_ids", &self.chosen.input_ids)?;
+        state.serialize_field("chosen_labels", &self.chosen.labels)?;
+        state.serialize_field("chosen_position_ids", &self.chosen.position_ids)?;
+        if !self.chosen.document_ids.is_empty() {
+            state.serialize_field("chosen_document_ids", &self.chosen.document_ids)?;
+        }
+        state.serialize_field("rejected_input_ids", &self.rejected.input_ids)?;
+        state.serialize_field("rejected_labels", &self.rejected.labels)?;
+        state.serialize_field("rejected_position_ids", &self.rejected.position_ids)?;
+        if !self.rejected.document_ids.is_empty() {
+            state.serialize_field("rejected_document_ids", &self.rejected.document_ids)?;
+        }
+        state.serialize_field("length", &self.length)?;
+        if !self.chosen.source_ids.is_empty() {
+            state.serialize_field("source_ids", &self.chosen.source_ids)?;
+        }
+        state.end()
+    }
+}
+
+impl TokenizedPair {
+    pub fn new(chosen: TokenizedInput, rejected: TokenizedInput) -> Self {
+        let length = chosen.length.max(rejected.length);
+        TokenizedPair {
+            chosen,
+            rejected,
+            length,
+        }
+    }
+}
+
+/// Masks the labels of the tokens shared with the prompt
+///
+/// The prompt is tokenized on its own, so only the common prefix is masked in case the
+/// tokenizer merges tokens across the prompt and response boundary.
+fn mask_prompt(input: &mut TokenizedInput, prompt_ids: &[i32], ignore_index: i32) {
+    let prompt_length = common_prefix(prompt_ids, &input.input_ids);
+    input.labels[..prompt_length].fill(ignore_index);
+}
+
+/// Renders the prompt with the generation prompt, and the prompt followed by each branch
+fn render_branch(
+    prompt: &[TextMessage],
+    response: Vec<TextMessage>,
+    ct: &template::ChatTemplate,
+) -> error::Result<String> {
+    let mut messages = prompt.to_vec();
+    messages.extend(response);
+    Ok(ct.apply(messages)?)
+}
+
+// Same as the TokenizedInput records, but renders the prompt and both branches of the record.
+// The prompt is always masked, so only the ignore index of the label policy applies.
+impl Tokenize for TokenizedPair {
+    fn render(item: &str, ct: &template::ChatTemplate, _: &LabelPolicy) -> error::Result<Rendered> {
+        let record: PreferenceRecord = serde_json::from_str(item)?;
+        let prompt = record.prompt.into_messages("user");
+        Ok(Rendered {
+            texts: vec![
+                ct.apply_with_generation_prompt(prompt.clone())?,
+                render_branch(&prompt, record.chosen.into_messages("assistant"), ct)?,
+                render_branch(&prompt, record.rejected.into_messages("assistant"), ct)?,
+            ],
+            labels: RecordLabels::default(),
+            id: record.id.map(source_id),
+        })
+    }
+    fn from_ids(ids: Vec<Vec<i32>>, _: &RecordLabels, policy: &LabelPolicy) -> error::Result<Self> {
+        let [prompt_ids, chosen, rejected] =
+            <[Vec<i32>; 3]>::try_from(ids).map_err(|ids| error::Error::TextCount {
+                expected: 3,
+                found: ids.len(),
+            })?;
+        let ignore_index = policy.ignore_index;
+        let labels = chosen.clone();
+        let mut chosen = TokenizedInput::with_labels(chosen, labels, ignore_index);
+        mask_prompt(&mut chosen, &prompt_ids, ignore_index);
+        let labels = rejected.clone();
+        let mut rejected = TokenizedInput::with_labels(rejected, labels, ignore_index);
+        mask_prompt(&mut rejected, &prompt_ids, ignore_index);
+        Ok(TokenizedPair::new(chosen, rejected))
+    }
+    fn push_separator(&mut self, id: i32, label: i32) {
+        self.chosen.push_separator(id, label);
+        self.rejected.push_separator(id, label);
+        self.length = self.chosen.length.max(self.rejected.length);
+    }
+    fn shift_labels(&mut self, ignore_index: i32) {
+        self.chosen.shift_labels(ignore_index);
+        self.rejected.shift_labels(ignore_index);
+    }
+    // the source ids of the bin are kept with the chosen branch
+    fn set_source(&mut self, source: String) {
+        self.chosen.set_source(source);
+    }
+    fn set_document_ids(&mut self) {
+        self.chosen.set_document_ids();
+        self.rejected.set_document_ids();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binpacking::Packable;
+
+    #[test]
+    fn test_parse_record() {
+        let item = r#"{"prompt": "Hi", "chosen": [{"role": "assistant", "content": "Hello!"}], "rejected": "Go away"}"#;
+        let record: PreferenceRecord = serde_json::from_str(item).unwrap();
+        let prompt = record.prompt.into_messages("user");
+        assert_eq!(prompt[0].role, "user");
+        assert_eq!(prompt[0].content, "Hi");
+        let rejected = record.rejected.into_messages("assistant");
+        assert_eq!(rejected[0].role, "assistant");
+        assert_eq!(rejected[0].content, "Go away");
+    }
+
+    #[test]
+    fn test_mask_prompt() {
+        let mut input = TokenizedInput::from_ids(vec![1, 2, 3, 4, 5]);
+        // the last prompt token was merged with the response
+        mask_prompt(&mut input, &[1, 2, 9], -100);
+        assert_eq!(input.labels, vec![-100, -100, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_pair_from_ids() {
+        let ids = vec![vec![1, 2], vec![1, 2, 3, 4], vec![1, 2, 5]];
+        let pair = <TokenizedPair as Tokenize>::from_ids(
+            ids.clone(),
+            &RecordLabels::default(),
+            &LabelPolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(pair.chosen.labels, vec![-100, -100, 3, 4]);
+        assert_eq!(pair.rejected.labels, vec![-100, -100, 5]);
+        assert_eq!(pair.length, 4);
+        let policy = LabelPolicy {
+            ignore_index: -1,
+            ..LabelPolicy::default()
+        };
+        let pair =
+            <TokenizedPair as Tokenize>::from_ids(ids, &RecordLabels::default(), &policy).unwrap();
+        assert_eq!(pair.chosen.labels, vec![-1, -1, 3, 4]);
+    }
+
+    #[test]
+    fn test_merge_pairs() {
+        let mut left = TokenizedPair::new(
+            TokenizedInput::from_ids(vec![1, 2, 3]),
+            TokenizedInput::from_ids(vec![1, 2]),
+        );
+        let right = TokenizedPair::new(
+            TokenizedInput::from_ids(vec![4]),
+            TokenizedInput::from_ids(vec![4, 5, 6, 7]),
+        );
+        assert_eq!(left.length, 3);
+        assert_eq!(right.length, 4);
+        left.merge(&right);
+        assert_eq!(left.length, 7);
+        assert_eq!(left.chosen.input_ids, vec![1, 2, 3, 4]);
+        assert_eq!(left.rejected.input_ids, vec![1, 2, 4, 5, 6, 7]);
+        assert_eq!(left.rejected.position_ids, vec![0, 1, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_serialize_pair() {
+        let pair = TokenizedPair::new(
+            TokenizedInput::from_ids(vec![1, 2, 3]),
+            TokenizedInput::from_ids(vec![1, 2]),
+        );
+        let json: serde_json::Value = serde_json::to_value(&pair).unwrap();
+        assert_eq!(json["length"], 3);
+        assert_eq!(json["rejected_input_ids"], serde_json::json!([1, 2]));
+        assert!(json.get("source_ids").is_none());
+    }
+}
@@ -17,6 +17,9 @@ pub(crate) struct ChatTemplateInputs<'a> {
 pub struct TextMessage {
     pub role: String,
     pub content: String,
+    /// Loss weight of the tokens of the message, with `--loss-weights`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f32>,
 }
 #[derive(Clone)]
 pub struct ChatTemplate {
@@ -26,41 +29,62 @@ pub struct ChatTemplate {
 }
 
 impl ChatTemplate {
-    pub(crate) fn new(
+    /// Compiles a jinja chat template, the bos and eos tokens are passed to it as
+    /// `bos_token` and `eos_token`
+    pub fn new(
         template: String,
         bos_token: Option<String>,
         eos_token: Option<String>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let env = Box::new(Environment::new());
 
         let template_str = template.into_boxed_str();
 
         // leaking env and template_str as read-only, static resources for performance.
-        let template = Box::leak(env)
-            .template_from_str(Box::leak(template_str))
-            .unwrap();
+        let template = Box::leak(env).template_from_str(Box::leak(template_str))?;
 
         // check if the `tools` variable is used in the template
 
-        Self {
+        Ok(Self {
             template,
             bos_token: bos_token.map(|token| token.as_str().to_string()),
             eos_token: eos_token.map(|token| token.as_str().to_string()),
-        }
+        })
     }
-    pub fn from_config(config: TokenizerConfig) -> Self {
+    pub fn from_config(config: TokenizerConfig) -> Result<Self, Error> {
         Self::new(
             config.chat_template,
             Some(config.bos_token),
             Some(config.eos_token),
         )
     }
+    pub fn bos_token(&self) -> Option<&str> {
+        self.bos_token.as_deref()
+    }
+    pub fn eos_token(&self) -> Option<&str> {
+        self.eos_token.as_deref()
+    }
     pub fn apply(&self, messages: Vec<TextMessage>) -> Result<String, Error> {
+        self.render(messages, false)
+    }
+    /// Renders the messages followed by the generation prompt, this is the text a model
+    /// sees before it starts to write the assistant response
+    pub fn apply_with_generation_prompt(
+        &self,
+        messages: Vec<TextMessage>,
+    ) -> Result<String, Error> {
+        self.render(messages, true)
+    }
+    fn render(
+        &self,
+        messages: Vec<TextMessage>,
+        add_generation_prompt: bool,
+    ) -> Result<String, Error> {
         self.template.render(ChatTemplateInputs {
             messages,
             bos_token: self.bos_token.as_deref(),
             eos_token: self.eos_token.as_deref(),
-            add_generation_prompt: false,
+            add_generation_prompt,
         })
     }
 }
@@ -76,7 +100,8 @@ mod tests {
             "Hello, {{ name }}!".to_string(),
             Some("BOS".to_string()),
             Some("EOS".to_string()),
-        );
+        )
+        .unwrap();
 
         assert_eq!(template.bos_token, Some("BOS".to_string()));
         assert_eq!(template.eos_token, Some("EOS".to_string()));
@@ -205,16 +230,17 @@ mod tests {
                 TextMessage {
                     role: "user".to_string(),
                     content: "What is the capital of Singapore?".to_string(),
+                    weight: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "I don't know, what is it?".to_string(),
+                    weight: None,
                 },
             ],
             bos_token: Some("<|begin_of_text|>"),
             eos_token: Some("<|eot_id|>"),
             add_generation_prompt: false,
-            ..Default::default()
         };
 
         let result = tmpl.unwrap().render(chat_template_inputs).unwrap();
@@ -258,24 +284,27 @@ mod tests {
                 TextMessage {
                     role: "user".to_string(),
                     content: "Hi!".to_string(),
+                    weight: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "Hello how can I help?".to_string(),
+                    weight: None,
                 },
                 TextMessage {
                     role: "user".to_string(),
                     content: "What is Deep Learning?".to_string(),
+                    weight: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "magic!".to_string(),
+                    weight: None,
                 },
             ],
             bos_token: Some("[BOS]"),
             eos_token: Some("[EOS]"),
             add_generation_prompt: true,
-            ..Default::default()
         };
 
         let result = tmpl.unwrap().render(chat_template_inputs).unwrap();
@@ -314,24 +343,29 @@ mod tests {
             source.to_string(),
             Some("[BOS]".to_string()),
             Some("[EOS]".to_string()),
-        );
+        )
+        .unwrap();
 
         let messages = vec![
             TextMessage {
                 role: "user".to_string(),
                 content: "Hi!".to_string(),
+                weight: None,
             },
             TextMessage {
                 role: "assistant".to_string(),
                 content: "Hello how can I help?".to_string(),
+                weight: None,
             },
             TextMessage {
                 role: "user".to_string(),
                 content: "What is Deep Learning?".to_string(),
+                weight: None,
             },
             TextMessage {
                 role: "assistant".to_string(),
                 content: "magic!".to_string(),
+                weight: None,
             },
         ];
 
@@ -343,6 +377,39 @@ mod tests {
         );
     }
     #[test]
+    fn test_apply_with_generation_prompt() {
+        let source = r#"
+        {% for message in messages %}
+            {% if message['role'] == 'user' %}
+                {{'### User:\n' + message['content']+'\n\n'}}
+            {% elif message['role'] == 'assistant' %}
+                {{'### Assistant:\n'  + message['content']}}
+            {% endif %}
+            {% if loop.last and add_generation_prompt %}
+                {{ '### Assistant:\n' }}
+            {% endif %}
+        {% endfor %}"#;
+
+        // trim all the whitespace
+        let source = source
+            .lines()
+            .map(|line| line.trim())
+            .collect::<Vec<&str>>()
+            .join("");
+
+        let ct = ChatTemplate::new(source.to_string(), None, None).unwrap();
+
+        let messages = vec![TextMessage {
+            role: "user".to_string(),
+            content: "Hi!".to_string(),
+            weight: None,
+        }];
+
+        let result = ct.apply_with_generation_prompt(messages).unwrap();
+
+        assert_eq!(result, "### User:\nHi!\n\n### Assistant:\n");
+    }
+    #[test]
     fn test_with_tokenize() {
         let source = r#"
         {% for message in messages %}
@@ -371,24 +438,29 @@ mod tests {
             source.to_string(),
             Some("[BOS]".to_string()),
             Some("[EOS]".to_string()),
-        );
+        )
+        .unwrap();
 
         let messages = vec![
             TextMessage {
                 role: "user".to_string(),
                 content: "Hi!".to_string(),
+                weight: None,
             },
             TextMessage {
                 role: "assistant".to_string(),
                 content: "Hello how can I help?".to_string(),
+                weight: None,
             },
             TextMessage {
                 role: "user".to_string(),
                 content: "What is Deep Learning?".to_string(),
+                weight: None,
             },
             TextMessage {
                 role: "assistant".to_string(),
                 content: "magic!".to_string(),
+                weight: None,
             },
         ];
 
@@ -406,7 +478,7 @@ mod tests {
             eos_token: "[EOS]".to_string(),
             chat_template: "Test template".to_string(),
         };
-        let ct = ChatTemplate::from_config(config);
+        let ct = ChatTemplate::from_config(config).unwrap();
         assert_eq!(ct.bos_token, Some("[BOS]".to_string()));
         assert_eq!(ct.eos_token, Some("[EOS]".to_string()));
         assert_eq!(ct.template.source(), "Test template");
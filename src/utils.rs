@@ -4,7 +4,19 @@ macro_rules! time_it {
         let start = std::time::Instant::now();
         let result = $code;
         let duration = start.elapsed();
-        println!("{}: {:?}", $task, duration);
+        eprintln!("{}: {:?}", $task, duration);
         result
     }};
 }
+
+/// Parses the value of a CLI option ignoring the case, eg. the format of the python bindings
+pub fn parse_value<T: clap::ValueEnum>(value: &str) -> Result<T, String> {
+    T::from_str(value, true).map_err(|_| {
+        let names: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|possible| possible.get_name().to_string())
+            .collect();
+        format!("{} is not one of {}", value, names.join(", "))
+    })
+}
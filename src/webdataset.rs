@@ -0,0 +1,174 @@
+// Handles the WebDataset output, tar shards where the files of a sample share a key
+use arrow::datatypes::Schema;
+use arrow::record_batch::RecordBatch;
+use std::cell::Cell;
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use tar::{Builder, Header};
+
+use crate::error::Result;
+use crate::npy::{descr, npy_header, NPY_HEADER_SIZE};
+use crate::writers::{create_output, list_item_type, list_parts, value_bytes, BatchWriter};
+
+/// Tar headers, and the data of every entry, take up whole blocks
+const TAR_BLOCK: usize = 512;
+
+/// Tar shard in the WebDataset layout, every bin is a sample with a 1-D .npy per column,
+/// eg. 000123.input_ids.npy and 000123.labels.npy
+///
+/// The keys are shared between the shards of a file through `next_key`, so they are unique
+/// across the shards.
+pub struct TarShard {
+    path: String,
+    builder: Builder<BufWriter<Box<dyn Write + Send>>>,
+    /// Names and npy type strings of the columns
+    columns: Vec<(String, &'static str)>,
+    next_key: Rc<Cell<usize>>,
+}
+
+impl TarShard {
+    pub fn create(path: &str, schema: &Arc<Schema>, next_key: Rc<Cell<usize>>) -> Result<Self> {
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field| {
+                Ok((
+                    field.name().clone(),
+                    descr(list_item_type(field.data_type())?)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(TarShard {
+            path: path.to_string(),
+            builder: Builder::new(BufWriter::new(create_output(path)?)),
+            columns,
+            next_key,
+        })
+    }
+
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut header = Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        // a fixed mtime keeps the shards reproducible
+        header.set_mtime(0);
+        self.builder.append_data(&mut header, name, data)?;
+        Ok(())
+    }
+}
+
+impl BatchWriter for TarShard {
+    fn write_batch(&mut self, batch: RecordBatch) -> Result<()> {
+        let columns: Vec<_> = batch
+            .columns()
+            .iter()
+            .map(|column| {
+                let (values, lengths) = list_parts(column)?;
+                let item_size = values.data_type().primitive_width().unwrap();
+                Ok((value_bytes(&values), lengths, item_size))
+            })
+            .collect::<Result<_>>()?;
+        let mut starts = vec![0; columns.len()];
+        for row in 0..batch.num_rows() {
+            let key = self.next_key.get();
+            self.next_key.set(key + 1);
+            for (index, ((bytes, lengths, item_size), start)) in
+                columns.iter().zip(starts.iter_mut()).enumerate()
+            {
+                let (name, descr) = &self.columns[index];
+                let size = lengths[row] * item_size;
+                let mut npy = npy_header(descr, &[lengths[row]]);
+                npy.extend_from_slice(&bytes[*start..*start + size]);
+                *start += size;
+                let name = format!("{:06}.{}.npy", key, name);
+                self.append(&name, &npy)?;
+            }
+        }
+        Ok(())
+    }
+    fn finish(self) -> Result<Vec<String>> {
+        self.builder.into_inner()?.flush()?;
+        Ok(vec![self.path])
+    }
+    /// A header block and the .npy padded to whole blocks per column
+    fn bin_bytes(&self, columns: &[(usize, usize)]) -> usize {
+        columns
+            .iter()
+            .map(|(_, bytes)| TAR_BLOCK + (NPY_HEADER_SIZE + bytes).next_multiple_of(TAR_BLOCK))
+            .sum()
+    }
+    /// The archive ends with two zero blocks
+    fn footer_bytes(&self) -> usize {
+        2 * TAR_BLOCK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binpacking::{Format, OutputOptions};
+    use crate::writers::fixture::{self, TestFolder};
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    fn write_tar(folder: &TestFolder, options: &OutputOptions) -> Vec<String> {
+        let next_key = Rc::new(Cell::new(0));
+        fixture::write_bins(
+            folder.path("file.tar"),
+            options,
+            vec![vec![1, 2], vec![3], vec![4, 5, 6]],
+            |path, schema, _| TarShard::create(path, schema, next_key.clone()),
+        )
+    }
+
+    #[test]
+    fn test_tar_shards() {
+        let folder = TestFolder::new("tar_shards");
+        let options = OutputOptions {
+            rows_per_shard: Some(2),
+            ..fixture::options(Format::Webdataset)
+        };
+        let paths = write_tar(&folder, &options);
+        assert_eq!(paths.len(), 2);
+
+        let mut archive = tar::Archive::new(File::open(&paths[1]).unwrap());
+        let mut entries = vec![];
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_str().unwrap().to_string();
+            let mut data = vec![];
+            entry.read_to_end(&mut data).unwrap();
+            entries.push((name, data));
+        }
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "000002.input_ids.npy",
+                "000002.labels.npy",
+                "000002.position_ids.npy"
+            ]
+        );
+        let (_, input_ids) = &entries[0];
+        assert_eq!(&input_ids[..128], &npy_header("<i4", &[3])[..]);
+        assert_eq!(&input_ids[128..], &[4, 0, 0, 0, 5, 0, 0, 0, 6, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_tar_shard_bytes() {
+        let folder = TestFolder::new("tar_shard_bytes");
+        // a sample is a header and a padded block for each of the 3 columns, and the archive
+        // ends with 2 blocks, so 2 samples fit
+        let max_shard_bytes = 2 * 6 * TAR_BLOCK + 2 * TAR_BLOCK;
+        let options = OutputOptions {
+            max_shard_bytes: Some(max_shard_bytes),
+            ..fixture::options(Format::Webdataset)
+        };
+        let sizes: Vec<usize> = write_tar(&folder, &options)
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len() as usize)
+            .collect();
+        assert_eq!(sizes, vec![max_shard_bytes, 8 * TAR_BLOCK]);
+    }
+}
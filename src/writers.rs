@@ -0,0 +1,763 @@
+// Handles writing the packed bins to the output files
+use arrow::array::{Array, ArrayRef, AsArray, GenericListArray, OffsetSizeTrait};
+use arrow::buffer::Buffer;
+use arrow::compute::{cast_with_options, CastOptions};
+use arrow::datatypes::{DataType, Schema};
+use arrow::error::ArrowError;
+use arrow::ipc::writer::{FileWriter, IpcWriteOptions, StreamWriter};
+use arrow::record_batch::RecordBatch;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use parquet::arrow::ArrowWriter;
+use parquet::file::properties::WriterProperties;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::binpacking::{OutputOptions, Packable};
+use crate::error::{Error, Result};
+use crate::input;
+
+/// A single output file that the bins are written to
+pub trait BinWriter<T: Packable> {
+    fn write_bin(&mut self, bin: T) -> Result<()>;
+    /// Returns the paths of the files written, the file of the shard first
+    fn finish(self) -> Result<Vec<String>>;
+    /// Bytes the bin adds to the shard, used to split the output by `max_shard_bytes`
+    fn bin_bytes(&self, bin: &T) -> Result<usize> {
+        Ok(bin.num_bytes())
+    }
+    /// Bytes written around the bins, eg. the end of a tar archive
+    fn footer_bytes(&self) -> usize {
+        0
+    }
+}
+
+/// An output file that is written a record batch at a time, the bins are collected into the
+/// batches by `Batched`
+pub trait BatchWriter {
+    fn write_batch(&mut self, batch: RecordBatch) -> Result<()>;
+    /// Returns the paths of the files written, the file of the shard first
+    fn finish(self) -> Result<Vec<String>>;
+    /// Bytes a bin adds to the file, from the number of values and the bytes of its columns in
+    /// the types of the schema
+    fn bin_bytes(&self, columns: &[(usize, usize)]) -> usize {
+        columns.iter().map(|(_, bytes)| bytes).sum()
+    }
+    /// Bytes written around the bins, eg. the end of a tar archive
+    fn footer_bytes(&self) -> usize {
+        0
+    }
+}
+
+/// Collects the bins into record batches of `batch_size` for a `BatchWriter`
+pub struct Batched<T: Packable, W: BatchWriter> {
+    writer: W,
+    schema: Arc<Schema>,
+    batch_size: usize,
+    record_vec: Vec<T>,
+}
+
+impl<T: Packable, W: BatchWriter> Batched<T, W> {
+    pub fn new(writer: W, schema: Arc<Schema>, batch_size: usize) -> Self {
+        Batched {
+            writer,
+            schema,
+            batch_size,
+            record_vec: Vec::with_capacity(batch_size),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let bins = std::mem::replace(&mut self.record_vec, Vec::with_capacity(self.batch_size));
+        self.writer
+            .write_batch(to_record_batch(bins, &self.schema)?)
+    }
+}
+
+impl<T: Packable, W: BatchWriter> BinWriter<T> for Batched<T, W> {
+    fn write_bin(&mut self, bin: T) -> Result<()> {
+        self.record_vec.push(bin);
+        if self.record_vec.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+    fn finish(mut self) -> Result<Vec<String>> {
+        if !self.record_vec.is_empty() {
+            self.flush()?;
+        }
+        self.writer.finish()
+    }
+    fn bin_bytes(&self, bin: &T) -> Result<usize> {
+        Ok(self.writer.bin_bytes(&column_sizes(bin, &self.schema)?))
+    }
+    fn footer_bytes(&self) -> usize {
+        self.writer.footer_bytes()
+    }
+}
+
+/// Creates the output file, or writes to stdout when the path is `-`
+pub fn create_output(path: &str) -> io::Result<Box<dyn Write + Send>> {
+    Ok(if path == input::STDIO {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(path)?)
+    })
+}
+
+/// Stem of the file name of a path, eg. out/file.arrow -> file
+fn file_stem(path: &str) -> &str {
+    Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .expect("Invalid file path")
+}
+
+/// Path with the stem of the file name replaced, keeping the extension
+fn with_stem(path: &str, stem: &str) -> String {
+    let path = Path::new(path);
+    let name = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{}.{}", stem, ext),
+        None => stem.to_string(),
+    };
+    path.with_file_name(name).to_str().unwrap().to_string()
+}
+
+/// Path of a shard, eg. out/file.arrow -> out/file-00001-of-00012.arrow
+pub fn shard_path(path: &str, index: usize, total: usize) -> String {
+    let stem = format!("{}-{:05}-of-{:05}", file_stem(path), index, total);
+    with_stem(path, &stem)
+}
+
+/// Splits the bins into numbered shards once a shard reaches `rows_per_shard` rows or
+/// `max_shard_bytes` bytes
+///
+/// The total number of shards is only known at the end, so the shards are written to a
+/// temporary name and renamed in `finish`, with the files written next to them, eg. the .idx
+/// of a megatron .bin. Without any limit, everything is written to `path` as before.
+pub struct ShardedOutput<T, W, F>
+where
+    T: Packable,
+    W: BinWriter<T>,
+    F: FnMut(&str) -> Result<W>,
+{
+    path: String,
+    rows_per_shard: Option<usize>,
+    max_shard_bytes: Option<usize>,
+    open: F,
+    current: Option<W>,
+    rows: usize,
+    bytes: usize,
+    paths: Vec<String>,
+    /// Files written by each finished shard
+    files: Vec<Vec<String>>,
+    _bin: PhantomData<T>,
+}
+
+impl<T, W, F> ShardedOutput<T, W, F>
+where
+    T: Packable,
+    W: BinWriter<T>,
+    F: FnMut(&str) -> Result<W>,
+{
+    pub fn new(path: String, options: &OutputOptions, open: F) -> Self {
+        ShardedOutput {
+            path,
+            rows_per_shard: options.rows_per_shard,
+            max_shard_bytes: options.max_shard_bytes,
+            open,
+            current: None,
+            rows: 0,
+            bytes: 0,
+            paths: Vec::new(),
+            files: Vec::new(),
+            _bin: PhantomData,
+        }
+    }
+
+    fn is_sharded(&self) -> bool {
+        self.rows_per_shard.is_some() || self.max_shard_bytes.is_some()
+    }
+
+    fn is_full(&self, bytes: usize) -> bool {
+        self.rows_per_shard.is_some_and(|rows| self.rows >= rows)
+            || self
+                .max_shard_bytes
+                .is_some_and(|max_bytes| self.bytes + bytes > max_bytes)
+    }
+
+    fn open_shard(&mut self) -> Result<()> {
+        let path = if self.is_sharded() {
+            let stem = format!("{}.{:05}.tmp", file_stem(&self.path), self.paths.len());
+            with_stem(&self.path, &stem)
+        } else {
+            self.path.clone()
+        };
+        let shard = (self.open)(&path)?;
+        self.bytes = shard.footer_bytes();
+        self.current = Some(shard);
+        self.paths.push(path);
+        self.rows = 0;
+        Ok(())
+    }
+
+    fn finish_shard(&mut self) -> Result<()> {
+        if let Some(shard) = self.current.take() {
+            self.files.push(shard.finish()?);
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, bin: T) -> Result<()> {
+        // the size is only needed to split by bytes, it may have to build the bin's columns
+        let by_bytes = self.max_shard_bytes.is_some();
+        let bin_bytes = |shard: &W| {
+            if by_bytes {
+                shard.bin_bytes(&bin)
+            } else {
+                Ok(0)
+            }
+        };
+        let bytes = self.current.as_ref().map(bin_bytes).transpose()?;
+        // a shard always holds at least one bin, even if it is larger than the limit
+        if bytes.is_some_and(|bytes| self.is_full(bytes)) {
+            self.finish_shard()?;
+        }
+        if self.current.is_none() {
+            self.open_shard()?;
+        }
+        let shard = self.current.as_mut().unwrap();
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => bin_bytes(shard)?,
+        };
+        shard.write_bin(bin)?;
+        self.rows += 1;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Finishes the last shard and returns the paths of all the files written
+    pub fn finish(mut self) -> Result<Vec<String>> {
+        // always write a file, even if there are no bins
+        if self.paths.is_empty() {
+            self.open_shard()?;
+        }
+        self.finish_shard()?;
+        if !self.is_sharded() {
+            return Ok(self.files.concat());
+        }
+        let total = self.paths.len();
+        let mut paths = Vec::new();
+        for (index, (tmp_path, files)) in self.paths.iter().zip(&self.files).enumerate() {
+            let tmp_stem = file_stem(tmp_path);
+            let stem = file_stem(&shard_path(&self.path, index, total)).to_string();
+            for file in files {
+                // the files next to the shard start with its stem, eg. file.00000.tmp_loss_mask.bin
+                let name = Path::new(file)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_prefix(tmp_stem))
+                    .expect("File not named after its shard");
+                let path = Path::new(file).with_file_name(format!("{}{}", stem, name));
+                fs::rename(file, &path)?;
+                paths.push(path.to_str().unwrap().to_string());
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Number of values and their size in bytes of every column of a bin, in the schema's types
+pub(crate) fn column_sizes<T: Packable>(
+    bin: &T,
+    schema: &Arc<Schema>,
+) -> Result<Vec<(usize, usize)>> {
+    let batch = to_record_batch(vec![bin.clone()], schema)?;
+    batch
+        .columns()
+        .iter()
+        .map(|column| {
+            let (values, _) = list_parts(column)?;
+            let bytes = match values.data_type().primitive_width() {
+                Some(width) => values.len() * width,
+                // the source ids are strings, with their offsets
+                None => values.to_data().get_slice_memory_size()?,
+            };
+            Ok((values.len(), bytes))
+        })
+        .collect()
+}
+
+/// Builds the batch from the bins, casting the `i32` columns to the types of the schema
+pub(crate) fn to_record_batch<T: Packable>(
+    bins: Vec<T>,
+    schema: &Arc<Schema>,
+) -> Result<RecordBatch> {
+    // an id that does not fit the type is an error instead of a null
+    let cast_options = CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    let columns = T::to_columns(bins, schema)
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| cast_with_options(column, field.data_type(), &cast_options))
+        .collect::<std::result::Result<_, ArrowError>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+/// Path of a file next to the output, eg. out/file.bin -> out/file_loss_mask.idx
+pub(crate) fn sibling_path(path: &str, suffix: &str, ext: &str) -> String {
+    let name = format!("{}{}.{}", file_stem(path), suffix, ext);
+    Path::new(path)
+        .with_file_name(name)
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Type of the values of a list column, all the columns of the bins are lists
+pub(crate) fn list_item_type(data_type: &DataType) -> Result<&DataType> {
+    match data_type {
+        DataType::List(item) | DataType::LargeList(item) => Ok(item.data_type()),
+        _ => Err(Error::Unsupported(format!(
+            "Column {} is not a list",
+            data_type
+        ))),
+    }
+}
+
+/// The values of a list column and the length of each list
+pub(crate) fn list_parts(column: &ArrayRef) -> Result<(ArrayRef, Vec<usize>)> {
+    fn parts<O: OffsetSizeTrait>(list: &GenericListArray<O>) -> (ArrayRef, Vec<usize>) {
+        let offsets = list.value_offsets();
+        let start = offsets[0].as_usize();
+        let end = offsets[offsets.len() - 1].as_usize();
+        let lengths = offsets
+            .windows(2)
+            .map(|w| (w[1] - w[0]).as_usize())
+            .collect();
+        (list.values().slice(start, end - start), lengths)
+    }
+    list_item_type(column.data_type())?;
+    Ok(match column.data_type() {
+        DataType::List(_) => parts(column.as_list::<i32>()),
+        _ => parts(column.as_list::<i64>()),
+    })
+}
+
+/// Little endian bytes of a primitive array
+pub(crate) fn value_bytes(values: &ArrayRef) -> Buffer {
+    let data = values.to_data();
+    let width = data
+        .data_type()
+        .primitive_width()
+        .expect("Column is not primitive");
+    data.buffers()[0].slice_with_length(data.offset() * width, data.len() * width)
+}
+
+/// Arrow IPC format of the arrow output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IpcFormat {
+    #[default]
+    Stream,
+    // with a footer for random access and memory-mapping
+    File,
+}
+
+/// Arrow IPC writer, the stream format or the file format with a footer
+pub enum IpcWriter<W: Write> {
+    Stream(StreamWriter<W>),
+    File(FileWriter<W>),
+}
+
+impl<W: Write> IpcWriter<W> {
+    pub fn try_new(
+        writer: W,
+        schema: &Schema,
+        options: &OutputOptions,
+    ) -> Result<Self, ArrowError> {
+        let write_options =
+            IpcWriteOptions::default().try_with_compression(options.ipc_compression)?;
+        Ok(match options.ipc_format {
+            IpcFormat::Stream => IpcWriter::Stream(StreamWriter::try_new_with_options(
+                writer,
+                schema,
+                write_options,
+            )?),
+            IpcFormat::File => IpcWriter::File(FileWriter::try_new_with_options(
+                writer,
+                schema,
+                write_options,
+            )?),
+        })
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<(), ArrowError> {
+        match self {
+            IpcWriter::Stream(writer) => writer.write(batch),
+            IpcWriter::File(writer) => writer.write(batch),
+        }
+    }
+
+    pub fn finish(&mut self) -> Result<(), ArrowError> {
+        match self {
+            IpcWriter::Stream(writer) => writer.finish(),
+            IpcWriter::File(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Arrow IPC output, the record batches are sent to a writer thread
+///
+/// The channel is bounded, so the packed bins wait for the writer in a few batches instead of
+/// piling up. The tokenized records of the whole file are still held in memory until they
+/// are packed.
+pub struct ArrowShard {
+    path: String,
+    sender: Sender<RecordBatch>,
+    handle: Option<JoinHandle<Result<(), ArrowError>>>,
+}
+
+impl ArrowShard {
+    pub fn create(path: &str, schema: &Arc<Schema>, options: &OutputOptions) -> Result<Self> {
+        let writer = IpcWriter::try_new(BufWriter::new(create_output(path)?), schema, options)?;
+        let (sender, receiver) = bounded(WRITER_CHANNEL_CAPACITY);
+        let handle = std::thread::spawn(move || write_batches(receiver, writer));
+        Ok(ArrowShard {
+            path: path.to_string(),
+            sender,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl BatchWriter for ArrowShard {
+    /// Sends the batch to the writer thread, or returns its error once it has stopped
+    fn write_batch(&mut self, batch: RecordBatch) -> Result<()> {
+        if self.sender.send(batch).is_err() {
+            // the writer thread only stops early on an error
+            if let Some(handle) = self.handle.take() {
+                handle
+                    .join()
+                    .map_err(|_| io::Error::other(WRITER_PANICKED))??;
+            }
+            return Err(io::Error::other("Arrow writer stopped").into());
+        }
+        Ok(())
+    }
+    fn finish(self) -> Result<Vec<String>> {
+        // closing the channel stops the writer thread
+        drop(self.sender);
+        if let Some(handle) = self.handle {
+            let msg = format!("Writing to {}", self.path);
+            time_it!(msg, handle.join()).map_err(|_| io::Error::other(WRITER_PANICKED))??;
+        }
+        Ok(vec![self.path])
+    }
+}
+
+const WRITER_PANICKED: &str = "Arrow writer panicked";
+
+/// Number of record batches waiting for the writer thread
+const WRITER_CHANNEL_CAPACITY: usize = 2;
+
+fn write_batches<W: Write>(
+    receiver: Receiver<RecordBatch>,
+    mut writer: IpcWriter<W>,
+) -> Result<(), ArrowError> {
+    while let Ok(batch) = receiver.recv() {
+        writer.write(&batch)?;
+    }
+    writer.finish()
+}
+
+/// Parquet file, the writer closes a row group every `row_group_size` bins
+pub struct ParquetShard {
+    path: String,
+    writer: ArrowWriter<Box<dyn Write + Send>>,
+}
+
+impl ParquetShard {
+    pub fn create(path: &str, schema: &Arc<Schema>, options: &OutputOptions) -> Result<Self> {
+        let props = WriterProperties::builder()
+            .set_compression(options.parquet_compression)
+            .set_max_row_group_size(options.row_group_size)
+            .build();
+        let writer = ArrowWriter::try_new(create_output(path)?, schema.clone(), Some(props))?;
+        Ok(ParquetShard {
+            path: path.to_string(),
+            writer,
+        })
+    }
+}
+
+// the size of a bin is the one before the encoding and compression of parquet, so the shards
+// usually end up smaller
+impl BatchWriter for ParquetShard {
+    fn write_batch(&mut self, batch: RecordBatch) -> Result<()> {
+        self.writer.write(&batch)?;
+        Ok(())
+    }
+    fn finish(self) -> Result<Vec<String>> {
+        self.writer.close()?;
+        Ok(vec![self.path])
+    }
+}
+
+/// One json object per bin
+pub struct JsonlShard {
+    path: String,
+    writer: BufWriter<Box<dyn Write + Send>>,
+}
+
+impl JsonlShard {
+    pub fn create(path: &str) -> Result<Self> {
+        Ok(JsonlShard {
+            path: path.to_string(),
+            writer: BufWriter::new(create_output(path)?),
+        })
+    }
+}
+
+impl<T: Packable> BinWriter<T> for JsonlShard {
+    fn write_bin(&mut self, bin: T) -> Result<()> {
+        let json = serde_json::to_string(&bin)?;
+        writeln!(self.writer, "{}", json)?;
+        Ok(())
+    }
+    fn finish(mut self) -> Result<Vec<String>> {
+        self.writer.flush()?;
+        Ok(vec![self.path])
+    }
+}
+
+/// Setup shared by the tests of the writers
+#[cfg(test)]
+pub(crate) mod fixture {
+    use super::*;
+    use crate::binpacking::Format;
+    use crate::conversations::TokenizedInput;
+    use std::path::PathBuf;
+
+    /// An empty temporary folder for the files of a test, removed when dropped
+    pub struct TestFolder(PathBuf);
+
+    impl TestFolder {
+        pub fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("collate_test_{}", name));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            TestFolder(root)
+        }
+
+        pub fn root(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        /// Path of a file in the folder
+        pub fn path(&self, name: &str) -> String {
+            self.0.join(name).to_str().unwrap().to_string()
+        }
+
+        pub fn read(&self, name: &str) -> Vec<u8> {
+            fs::read(self.0.join(name)).unwrap()
+        }
+
+        /// Sorted names of the files in the folder
+        pub fn file_names(&self) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for TestFolder {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Options of the tests, bins of up to 8 tokens written in batches of 10
+    pub fn options(format: Format) -> OutputOptions {
+        OutputOptions {
+            format,
+            max_length: 8,
+            batch_size: 10,
+            ..OutputOptions::default()
+        }
+    }
+
+    /// Writes a bin per list of ids to the shards of `create` and returns the written paths
+    pub fn write_bins<W: BatchWriter>(
+        path: String,
+        options: &OutputOptions,
+        ids: Vec<Vec<i32>>,
+        create: impl Fn(&str, &Arc<Schema>, &OutputOptions) -> Result<W>,
+    ) -> Vec<String> {
+        let schema = Arc::new(TokenizedInput::schema(&options.dtypes));
+        let mut output = ShardedOutput::new(path, options, |path: &str| {
+            let writer = create(path, &schema, options)?;
+            Ok(Batched::new(writer, schema.clone(), options.batch_size))
+        });
+        for ids in ids {
+            output
+                .write(TokenizedInput::from_ids(ids.to_vec()))
+                .unwrap();
+        }
+        output.finish().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::{self, TestFolder};
+    use super::*;
+    use crate::binpacking::{Dtype, Format, TokenDtypes};
+    use crate::conversations::TokenizedInput;
+
+    fn options(rows_per_shard: Option<usize>, max_shard_bytes: Option<usize>) -> OutputOptions {
+        OutputOptions {
+            rows_per_shard,
+            max_shard_bytes,
+            ..fixture::options(Format::Jsonl)
+        }
+    }
+
+    fn write_jsonl(folder: &TestFolder, options: &OutputOptions, bins: usize) -> Vec<String> {
+        let mut output = ShardedOutput::new(folder.path("file.jsonl"), options, JsonlShard::create);
+        for _ in 0..bins {
+            // 4 tokens, 48 bytes
+            output
+                .write(TokenizedInput::from_ids(vec![1, 2, 3, 4]))
+                .unwrap();
+        }
+        output.finish().unwrap()
+    }
+
+    fn names(paths: &[String]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| {
+                let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+                name.to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shard_path() {
+        assert_eq!(
+            shard_path("out/file.arrow", 1, 12),
+            "out/file-00001-of-00012.arrow"
+        );
+    }
+
+    #[test]
+    fn test_unsharded() {
+        let folder = TestFolder::new("unsharded");
+        let paths = write_jsonl(&folder, &options(None, None), 5);
+        assert_eq!(names(&paths), vec!["file.jsonl"]);
+        assert_eq!(fs::read_to_string(&paths[0]).unwrap().lines().count(), 5);
+    }
+
+    #[test]
+    fn test_rows_per_shard() {
+        let folder = TestFolder::new("rows_per_shard");
+        let paths = write_jsonl(&folder, &options(Some(2), None), 5);
+        assert_eq!(
+            names(&paths),
+            vec![
+                "file-00000-of-00003.jsonl",
+                "file-00001-of-00003.jsonl",
+                "file-00002-of-00003.jsonl"
+            ]
+        );
+        let rows: Vec<usize> = paths
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap().lines().count())
+            .collect();
+        assert_eq!(rows, vec![2, 2, 1]);
+        // no temporary files are left behind
+        assert_eq!(folder.file_names(), names(&paths));
+    }
+
+    #[test]
+    fn test_max_shard_bytes() {
+        let folder = TestFolder::new("max_shard_bytes");
+        let paths = write_jsonl(&folder, &options(None, Some(100)), 5);
+        let rows: Vec<usize> = paths
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap().lines().count())
+            .collect();
+        assert_eq!(rows, vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn test_ipc_file_compressed() {
+        let folder = TestFolder::new("ipc_file_compressed");
+        let options = OutputOptions {
+            ipc_format: IpcFormat::File,
+            ipc_compression: Some(arrow::ipc::CompressionType::ZSTD),
+            batch_size: 2,
+            ..fixture::options(Format::Arrow)
+        };
+        let paths = fixture::write_bins(
+            folder.path("file.arrow"),
+            &options,
+            vec![vec![1, 2, 3], vec![4], vec![5, 6]],
+            ArrowShard::create,
+        );
+
+        let reader =
+            arrow::ipc::reader::FileReader::try_new(File::open(&paths[0]).unwrap(), None).unwrap();
+        assert_eq!(reader.num_batches(), 2);
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 3);
+    }
+
+    #[test]
+    fn test_arrow_shard_bytes() {
+        let folder = TestFolder::new("arrow_shard_bytes");
+        // 4 tokens in 3 columns of 2 bytes once cast, 24 bytes instead of the 48 in memory
+        let options = OutputOptions {
+            dtypes: TokenDtypes::new(Dtype::Auto, 32000, 8, 10, -100).unwrap(),
+            max_shard_bytes: Some(50),
+            ..fixture::options(Format::Arrow)
+        };
+        let paths = fixture::write_bins(
+            folder.path("file.arrow"),
+            &options,
+            vec![vec![1, 2, 3, 4]; 5],
+            ArrowShard::create,
+        );
+        assert_eq!(paths.len(), 3);
+    }
+
+    #[test]
+    fn test_to_record_batch_dtypes() {
+        let dtypes = TokenDtypes::new(Dtype::Auto, 32000, 8, 10, -100).unwrap();
+        let schema = Arc::new(TokenizedInput::schema(&dtypes));
+        let batch =
+            to_record_batch(vec![TokenizedInput::from_ids(vec![1, 31999])], &schema).unwrap();
+        let labels = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<arrow::array::ListArray>()
+            .unwrap()
+            .value(0);
+        let labels = labels
+            .as_any()
+            .downcast_ref::<arrow::array::Int16Array>()
+            .unwrap();
+        assert_eq!(labels.values(), &[-100, 31999]);
+        assert_eq!(batch.schema(), schema);
+    }
+}